@@ -0,0 +1,437 @@
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::{Decompress, FlushDecompress, Status};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+
+use crate::boundary::ZIP_HEADER;
+use crate::file::with_file_data;
+
+/// Size of the uncompressed blocks hashed in `AppxBlockMap.xml`.
+pub const APPX_BLOCK_SIZE: usize = 64 * 1024;
+
+const END_OF_CENTRAL_DIRECTORY: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
+const CENTRAL_DIRECTORY_HEADER: [u8; 4] = [0x50, 0x4b, 0x01, 0x02];
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+
+// Empty stored block that ends every flushed block of deflate stream
+const FLUSH_MARKER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+// Package files that are not listed in the block map
+const FOOTPRINT_FILES: [&str; 3] = [
+    "AppxBlockMap.xml",
+    "AppxSignature.p7x",
+    "[Content_Types].xml",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppxBlock {
+    /// SHA-256 of the uncompressed block
+    pub hash: Vec<u8>,
+
+    /// Size of the compressed block, `None` for stored files
+    pub compressed_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppxFile {
+    /// Name in the package with `\` as separator
+    pub name: String,
+    pub size: usize,
+
+    /// Size of the local file header
+    pub lfh_size: usize,
+    pub blocks: Vec<AppxBlock>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn slice(data: &[u8], offset: usize, size: usize) -> io::Result<&[u8]> {
+    offset
+        .checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| invalid_data(format!("zip record at {} is out of bounds", offset)))
+}
+
+/// Lists the blocks of every file in the `zip` package.
+///
+/// Compressed files must be deflated in independently flushed 64 KiB blocks,
+/// as done by MSIX packaging tools, so that compressed block sizes exist.
+pub fn appx_block_map(zip: &[u8]) -> io::Result<Vec<AppxFile>> {
+    if zip.len() < END_OF_CENTRAL_DIRECTORY_SIZE {
+        return Err(invalid_data("zip is too short".to_string()));
+    }
+
+    // The end of central directory record is followed by a comment of up to
+    // 64 KiB
+    let search_start = zip
+        .len()
+        .saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE + u16::MAX as usize);
+    let eocd = (search_start..=zip.len() - END_OF_CENTRAL_DIRECTORY_SIZE)
+        .rev()
+        .find(|&offset| zip[offset..offset + 4] == END_OF_CENTRAL_DIRECTORY)
+        .ok_or_else(|| invalid_data("end of central directory not found".to_string()))?;
+
+    let record = &zip[eocd..eocd + END_OF_CENTRAL_DIRECTORY_SIZE];
+    let entries = LittleEndian::read_u16(&record[10..]) as usize;
+    let mut offset = LittleEndian::read_u32(&record[16..]) as usize;
+    if entries == u16::MAX as usize || offset == u32::MAX as usize {
+        return Err(invalid_data("zip64 is not supported".to_string()));
+    }
+
+    let mut files = Vec::with_capacity(entries);
+    for _ in 0..entries {
+        let header = slice(zip, offset, CENTRAL_DIRECTORY_HEADER_SIZE)?;
+        if header[..4] != CENTRAL_DIRECTORY_HEADER {
+            return Err(invalid_data(format!(
+                "invalid central directory header at {}",
+                offset
+            )));
+        }
+
+        let method = LittleEndian::read_u16(&header[10..]);
+        let compressed_size = LittleEndian::read_u32(&header[20..]) as usize;
+        let size = LittleEndian::read_u32(&header[24..]) as usize;
+        let name_len = LittleEndian::read_u16(&header[28..]) as usize;
+        let extra_len = LittleEndian::read_u16(&header[30..]) as usize;
+        let comment_len = LittleEndian::read_u16(&header[32..]) as usize;
+        let local_offset = LittleEndian::read_u32(&header[42..]) as usize;
+        let name = slice(zip, offset + CENTRAL_DIRECTORY_HEADER_SIZE, name_len)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset += CENTRAL_DIRECTORY_HEADER_SIZE + name_len + extra_len + comment_len;
+
+        if name.ends_with('/') || FOOTPRINT_FILES.contains(&name.as_str()) {
+            continue;
+        }
+
+        let local = slice(zip, local_offset, LOCAL_HEADER_SIZE)?;
+        if local[..4] != ZIP_HEADER {
+            return Err(invalid_data(format!("invalid local header of {}", name)));
+        }
+        let lfh_size = LOCAL_HEADER_SIZE
+            + LittleEndian::read_u16(&local[26..]) as usize
+            + LittleEndian::read_u16(&local[28..]) as usize;
+        let data = slice(zip, local_offset + lfh_size, compressed_size)?;
+
+        let blocks = match method {
+            METHOD_STORED => data
+                .chunks(APPX_BLOCK_SIZE)
+                .map(|block| AppxBlock {
+                    hash: Sha256::digest(block).to_vec(),
+                    compressed_size: None,
+                })
+                .collect(),
+            METHOD_DEFLATE => deflated_blocks(&name, data, size)?,
+            _ => {
+                return Err(invalid_data(format!(
+                    "unsupported compression method {} of {}",
+                    method, name
+                )))
+            }
+        };
+
+        files.push(AppxFile {
+            name: name.replace('/', "\\"),
+            size,
+            lfh_size,
+            blocks,
+        });
+    }
+
+    Ok(files)
+}
+
+// Every block but the last one ends with a flush marker. The input is fed to
+// the decompressor up to the next marker until the block is complete, so
+// that compressed block sizes don't depend on how far the decompressor reads
+// ahead.
+fn deflated_blocks(name: &str, data: &[u8], size: usize) -> io::Result<Vec<AppxBlock>> {
+    let not_aligned = || invalid_data(format!("{} isn't deflated in 64 KiB blocks", name));
+    let mut decompress = Decompress::new(false);
+    let mut block = Vec::with_capacity(APPX_BLOCK_SIZE);
+    let mut blocks = Vec::with_capacity(size.div_ceil(APPX_BLOCK_SIZE));
+
+    let mut block_start = 0;
+    let mut remaining = size;
+    while remaining != 0 {
+        let block_size = remaining.min(APPX_BLOCK_SIZE);
+        remaining -= block_size;
+
+        block.clear();
+        let mut fed = block_start;
+        while block.len() < block_size {
+            if fed == data.len() {
+                return Err(invalid_data(format!("{} is truncated", name)));
+            }
+
+            let end = if remaining == 0 {
+                data.len()
+            } else {
+                data[fed..]
+                    .windows(FLUSH_MARKER.len())
+                    .position(|window| window == FLUSH_MARKER)
+                    .map(|position| fed + position + FLUSH_MARKER.len())
+                    .ok_or_else(not_aligned)?
+            };
+
+            while (decompress.total_in() as usize) < end {
+                block.reserve(APPX_BLOCK_SIZE);
+                let consumed = decompress.total_in() as usize;
+                let status = decompress
+                    .decompress_vec(&data[consumed..end], &mut block, FlushDecompress::None)
+                    .map_err(|err| invalid_data(format!("{}: {}", name, err)))?;
+                if block.len() > block_size {
+                    return Err(not_aligned());
+                }
+                if status == Status::StreamEnd {
+                    break;
+                }
+            }
+            fed = end;
+        }
+
+        blocks.push(AppxBlock {
+            hash: Sha256::digest(&block).to_vec(),
+            compressed_size: Some(fed - block_start),
+        });
+        block_start = fed;
+    }
+
+    Ok(blocks)
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Serializes `files` as `AppxBlockMap.xml`.
+pub fn appx_block_map_xml(files: &[AppxFile]) -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n",
+        "<BlockMap xmlns=\"http://schemas.microsoft.com/appx/2010/blockmap\" ",
+        "HashMethod=\"http://www.w3.org/2001/04/xmlenc#sha256\">\n"
+    ));
+
+    for file in files {
+        xml += &format!(
+            "  <File Name=\"{}\" Size=\"{}\" LfhSize=\"{}\">\n",
+            escape_xml(&file.name),
+            file.size,
+            file.lfh_size
+        );
+        for block in &file.blocks {
+            xml += &format!("    <Block Hash=\"{}\"", base64::encode(&block.hash));
+            if let Some(size) = block.compressed_size {
+                xml += &format!(" Size=\"{}\"", size);
+            }
+            xml += "/>\n";
+        }
+        xml += "  </File>\n";
+    }
+
+    xml += "</BlockMap>\n";
+    xml
+}
+
+/// Lists the blocks of every file in the package at `path`.
+pub fn appx_block_map_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<AppxFile>> {
+    with_file_data(path, appx_block_map)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_data;
+    use byteorder::WriteBytesExt;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    // Deflates every 64 KiB block with a full flush like MSIX tools do
+    fn deflate_blocks(data: &[u8]) -> (Vec<u8>, Vec<usize>) {
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut output = Vec::with_capacity(data.len() + 1024);
+        let mut sizes = Vec::new();
+
+        let blocks: Vec<&[u8]> = data.chunks(APPX_BLOCK_SIZE).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            let last = i == blocks.len() - 1;
+            let flush = if last {
+                FlushCompress::Finish
+            } else {
+                FlushCompress::Full
+            };
+
+            let start = output.len();
+            let consumed = compress.total_in();
+            loop {
+                let input = &block[(compress.total_in() - consumed) as usize..];
+                output.reserve(64 * 1024);
+                let status = compress.compress_vec(input, &mut output, flush).unwrap();
+
+                let done = compress.total_in() - consumed == block.len() as u64
+                    && if last {
+                        status == Status::StreamEnd
+                    } else {
+                        output.len() < output.capacity()
+                    };
+                if done {
+                    break;
+                }
+            }
+            sizes.push(output.len() - start);
+        }
+
+        (output, sizes)
+    }
+
+    fn build_zip(entries: &[(&str, &[u8], bool)]) -> (Vec<u8>, Vec<Vec<usize>>) {
+        let mut zip = Vec::new();
+        let mut central_directory = Vec::new();
+        let mut compressed_sizes = Vec::new();
+
+        for &(name, data, deflate) in entries {
+            let (compressed, sizes) = if deflate {
+                deflate_blocks(data)
+            } else {
+                (data.to_vec(), vec![])
+            };
+            compressed_sizes.push(sizes);
+
+            let offset = zip.len() as u32;
+            let method = if deflate {
+                METHOD_DEFLATE
+            } else {
+                METHOD_STORED
+            };
+
+            zip.extend_from_slice(&ZIP_HEADER);
+            zip.extend_from_slice(&[20, 0, 0, 0]);
+            zip.write_u16::<LittleEndian>(method).unwrap();
+            zip.extend_from_slice(&[0; 8]);
+            zip.write_u32::<LittleEndian>(compressed.len() as u32)
+                .unwrap();
+            zip.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            zip.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+            zip.write_u16::<LittleEndian>(0).unwrap();
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(&compressed);
+
+            central_directory.extend_from_slice(&CENTRAL_DIRECTORY_HEADER);
+            central_directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            central_directory.write_u16::<LittleEndian>(method).unwrap();
+            central_directory.extend_from_slice(&[0; 8]);
+            central_directory
+                .write_u32::<LittleEndian>(compressed.len() as u32)
+                .unwrap();
+            central_directory
+                .write_u32::<LittleEndian>(data.len() as u32)
+                .unwrap();
+            central_directory
+                .write_u16::<LittleEndian>(name.len() as u16)
+                .unwrap();
+            central_directory.extend_from_slice(&[0; 12]);
+            central_directory.write_u32::<LittleEndian>(offset).unwrap();
+            central_directory.extend_from_slice(name.as_bytes());
+        }
+
+        let central_directory_offset = zip.len() as u32;
+        zip.extend_from_slice(&central_directory);
+        zip.extend_from_slice(&END_OF_CENTRAL_DIRECTORY);
+        zip.extend_from_slice(&[0; 4]);
+        zip.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+        zip.write_u16::<LittleEndian>(entries.len() as u16).unwrap();
+        zip.write_u32::<LittleEndian>(central_directory.len() as u32)
+            .unwrap();
+        zip.write_u32::<LittleEndian>(central_directory_offset)
+            .unwrap();
+        zip.write_u16::<LittleEndian>(0).unwrap();
+
+        (zip, compressed_sizes)
+    }
+
+    #[test]
+    fn it_lists_package_blocks() {
+        // Partially compressible
+        let mut payload = random_data(0x2545f4914f6cdd1d, 200_000);
+        for b in payload.iter_mut().step_by(3) {
+            *b = 0;
+        }
+
+        let (zip, compressed_sizes) = build_zip(&[
+            ("[Content_Types].xml", b"<Types/>", true),
+            ("AppxManifest.xml", b"<Package/>", false),
+            ("Assets/", b"", false),
+            ("Assets/App & Logo.bin", &payload, true),
+            ("Stored.bin", &payload[..70_000], false),
+            ("Empty.txt", b"", false),
+        ]);
+
+        let files = appx_block_map(&zip).unwrap();
+        let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "AppxManifest.xml",
+                "Assets\\App & Logo.bin",
+                "Stored.bin",
+                "Empty.txt"
+            ]
+        );
+
+        let deflated = &files[1];
+        assert_eq!(deflated.size, payload.len());
+        assert_eq!(deflated.lfh_size, LOCAL_HEADER_SIZE + 21);
+        assert_eq!(deflated.blocks.len(), 4);
+        for (block, (data, size)) in deflated.blocks.iter().zip(
+            payload
+                .chunks(APPX_BLOCK_SIZE)
+                .zip(compressed_sizes[3].iter()),
+        ) {
+            assert_eq!(block.hash, Sha256::digest(data).to_vec());
+            assert_eq!(block.compressed_size, Some(*size));
+        }
+
+        let stored = &files[2];
+        assert_eq!(stored.blocks.len(), 2);
+        assert_eq!(
+            stored.blocks[1],
+            AppxBlock {
+                hash: Sha256::digest(&payload[APPX_BLOCK_SIZE..70_000]).to_vec(),
+                compressed_size: None,
+            }
+        );
+        assert!(files[3].blocks.is_empty());
+
+        let xml = appx_block_map_xml(&files);
+        assert!(xml.contains("<File Name=\"Assets\\App &amp; Logo.bin\" Size=\"200000\""));
+        assert!(xml.contains(&format!(
+            "<Block Hash=\"{}\"/>",
+            base64::encode(&stored.blocks[0].hash)
+        )));
+        assert!(xml.contains("<File Name=\"Empty.txt\" Size=\"0\" LfhSize=\"39\">\n  </File>"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_packages() {
+        assert!(appx_block_map(b"not a zip file at all, really").is_err());
+
+        // Deflated without flushes at 64 KiB
+        let data = vec![7u8; 3 * APPX_BLOCK_SIZE];
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut compressed = Vec::with_capacity(data.len());
+        compress
+            .compress_vec(&data, &mut compressed, FlushCompress::Finish)
+            .unwrap();
+        assert!(deflated_blocks("file", &compressed, data.len()).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use sha2::digest::DynDigest;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use crate::{BlockmapV3, Chunk, Chunker, ChunkerOptions, GenericChunker, Stats};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Chunks everything read from `reader` and returns its blockmap. Use
+/// `BlockmapV3::to_v2` for electron-updater.
+///
+/// Chunking happens on the calling task in `BUFFER_SIZE` steps, so the
+/// executor is never blocked for long and no extra threads are spawned.
+pub async fn chunk_async_reader<R: AsyncRead + Unpin>(
+    mut reader: R,
+    options: ChunkerOptions,
+) -> io::Result<(Stats, BlockmapV3)> {
+    let mut chunker = Chunker::new(options.clone());
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    loop {
+        let bytes_read = reader.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        chunker.update(&buffer[..bytes_read]);
+    }
+
+    let stats = chunker.finalize_reset();
+    let chunks: Vec<Chunk> = chunker.collect();
+    let blockmap = BlockmapV3::from_chunks(&options, &stats, &chunks);
+    Ok((stats, blockmap))
+}
+
+/// Feeds written data to `update`. Call `finalize_reset` after shutting the
+/// writer down.
+impl<C: DynDigest + Unpin, F: DynDigest + Unpin> AsyncWrite for GenericChunker<C, F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().update(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{reference_chunks, sample_data};
+
+    #[tokio::test]
+    async fn it_chunks_async_readers() {
+        let data = sample_data(256 * 1024);
+
+        let (expected_stats, expected) = reference_chunks(&data, ChunkerOptions::default());
+
+        let (stats, blockmap) = chunk_async_reader(&data[..], ChunkerOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(stats.sha512, expected_stats.sha512);
+        assert_eq!(blockmap.size, data.len());
+        assert_eq!(
+            blockmap,
+            BlockmapV3::from_chunks(&ChunkerOptions::default(), &expected_stats, &expected)
+        );
+
+        let mut chunker = Chunker::new(ChunkerOptions::default());
+        tokio::io::copy(&mut &data[..], &mut chunker).await.unwrap();
+        let stats = chunker.finalize_reset();
+        let chunks: Vec<Chunk> = chunker.collect();
+        assert_eq!(stats.sha512, expected_stats.sha512);
+        assert_eq!(chunks, expected);
+    }
+}
@@ -0,0 +1,543 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::encoding::{decode, encode};
+use crate::table::POLYNOMIAL;
+use crate::{
+    find_duplicates, BlockmapCompression, BlockmapEncoding, BlockmapError, Chunk, ChunkerOptions,
+    ChunkingAlgorithm, Stats,
+};
+
+pub const BLOCKMAP_VERSION: &str = "2";
+pub const BLOCKMAP_V3_VERSION: &str = "3";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockmapFile {
+    pub name: String,
+    pub offset: usize,
+    #[serde(with = "crate::encoding::checksums")]
+    pub checksums: Vec<String>,
+    pub sizes: Vec<usize>,
+}
+
+/// Parameters that were used to produce the blockmap. Two blockmaps can only
+/// share chunks if they were generated with the same parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkerParameters {
+    // Blockmaps generated before the field was introduced used Rabin
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+    pub window_size: usize,
+    pub min_chunk: usize,
+    pub avg_chunk: usize,
+    pub max_chunk: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub normalization: u32,
+    pub detect_zip_boundary: bool,
+
+    // Serialized as a hex string since it doesn't fit into JavaScript number
+    pub polynomial: String,
+    pub digest: String,
+
+    // The key itself is never stored, the updater has to know it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keyed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+}
+
+impl ChunkerParameters {
+    /// Returns the first field that differs between `self` and `other`.
+    pub fn check_compatible(
+        &self,
+        other: &ChunkerParameters,
+    ) -> Result<(), IncompatibleParameters> {
+        let (a, b) = (self, other);
+        let field = if a.algorithm != b.algorithm {
+            "algorithm"
+        } else if a.window_size != b.window_size {
+            "windowSize"
+        } else if a.min_chunk != b.min_chunk {
+            "minChunk"
+        } else if a.avg_chunk != b.avg_chunk {
+            "avgChunk"
+        } else if a.max_chunk != b.max_chunk {
+            "maxChunk"
+        } else if a.normalization != b.normalization {
+            "normalization"
+        } else if a.detect_zip_boundary != b.detect_zip_boundary {
+            "detectZipBoundary"
+        } else if a.polynomial != b.polynomial {
+            "polynomial"
+        } else if a.digest != b.digest {
+            "digest"
+        } else if a.keyed != b.keyed {
+            "keyed"
+        } else if a.salt != b.salt {
+            "salt"
+        } else {
+            return Ok(());
+        };
+
+        Err(IncompatibleParameters { field })
+    }
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+fn default_algorithm() -> String {
+    ChunkingAlgorithm::Rabin.to_string()
+}
+
+impl From<&ChunkerOptions> for ChunkerParameters {
+    fn from(options: &ChunkerOptions) -> Self {
+        // Gear hash has neither a window nor a polynomial
+        let (window_size, polynomial) = match options.algorithm {
+            ChunkingAlgorithm::Rabin => (options.window_size, format!("{:#018x}", POLYNOMIAL)),
+            ChunkingAlgorithm::FastCdc => (0, String::new()),
+        };
+
+        Self {
+            algorithm: options.algorithm.to_string(),
+            window_size,
+            min_chunk: options.min_chunk,
+            avg_chunk: options.avg_chunk,
+            max_chunk: options.max_chunk,
+            normalization: options.normalization_level(),
+            detect_zip_boundary: options.detect_zip_boundary,
+            polynomial,
+            digest: options.chunk_digest.to_string(),
+            keyed: !options.chunk_digest_key.is_empty(),
+            salt: if options.chunk_digest_salt.is_empty() {
+                None
+            } else {
+                Some(base64::encode(&options.chunk_digest_salt))
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Blockmap {
+    pub version: String,
+    pub files: Vec<BlockmapFile>,
+
+    // Ignored by electron-updater
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<ChunkerParameters>,
+
+    // Embedded Ed25519 signature of the rest of the blockmap, see
+    // `VersionedBlockmap::canonical_bytes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IncompatibleParameters {
+    pub field: &'static str,
+}
+
+impl fmt::Display for IncompatibleParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "blockmaps were generated with different `{}`",
+            self.field
+        )
+    }
+}
+
+impl std::error::Error for IncompatibleParameters {}
+
+impl Blockmap {
+    pub fn new(parameters: ChunkerParameters, files: Vec<BlockmapFile>) -> Self {
+        Self {
+            version: BLOCKMAP_VERSION.to_string(),
+            files,
+            parameters: Some(parameters),
+            signature: None,
+        }
+    }
+
+    /// Checks that chunks of `self` and `other` can be compared. Blockmaps
+    /// without parameters (e.g. generated by older versions or other tools)
+    /// are assumed to be compatible.
+    pub fn check_compatible(&self, other: &Blockmap) -> Result<(), IncompatibleParameters> {
+        match (&self.parameters, &other.parameters) {
+            (Some(a), Some(b)) => a.check_compatible(b),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Chunks of a blockmap that can be copied from an older version of the
+/// file, see `Blockmap::diff`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockmapDiff {
+    pub chunks: usize,
+    pub reused_chunks: usize,
+    pub size: usize,
+
+    // Bytes of the chunks that are not in the old version
+    pub download_size: usize,
+}
+
+impl Blockmap {
+    /// Compares the chunks with the blockmap of the `old` version. Fails
+    /// instead of reporting no reuse if the blockmaps were generated with
+    /// different parameters.
+    pub fn diff(&self, old: &Blockmap) -> Result<BlockmapDiff, IncompatibleParameters> {
+        self.check_compatible(old)?;
+
+        let old_chunks: HashSet<(&str, usize)> = old
+            .files
+            .iter()
+            .flat_map(|file| {
+                file.checksums
+                    .iter()
+                    .map(String::as_str)
+                    .zip(file.sizes.iter().copied())
+            })
+            .collect();
+
+        let mut diff = BlockmapDiff {
+            chunks: 0,
+            reused_chunks: 0,
+            size: 0,
+            download_size: 0,
+        };
+        for file in &self.files {
+            for (checksum, &size) in file.checksums.iter().zip(&file.sizes) {
+                diff.chunks += 1;
+                diff.size = diff.size.saturating_add(size);
+                if old_chunks.contains(&(checksum.as_str(), size)) {
+                    diff.reused_chunks += 1;
+                } else {
+                    diff.download_size = diff.download_size.saturating_add(size);
+                }
+            }
+        }
+        Ok(diff)
+    }
+}
+
+/// File in a `BlockmapV3`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockmapV3File {
+    pub name: String,
+    pub offset: usize,
+
+    // Offset of every chunk from the start of the input, so that clients
+    // don't have to add up the sizes
+    pub offsets: Vec<usize>,
+    pub sizes: Vec<usize>,
+    #[serde(with = "crate::encoding::checksums")]
+    pub checksums: Vec<String>,
+
+    // Chunks that repeat an earlier chunk of the file, as
+    // `[index, index of the first occurrence]` pairs. Every other chunk has
+    // to be fetched once, see `find_duplicates`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dedup: Vec<(usize, usize)>,
+}
+
+impl BlockmapV3File {
+    pub fn new(name: String, offset: usize, chunks: &[Chunk]) -> Self {
+        let mut offsets = Vec::with_capacity(chunks.len());
+        let mut chunk_offset = offset;
+        for chunk in chunks {
+            offsets.push(chunk_offset);
+            chunk_offset += chunk.size;
+        }
+
+        Self {
+            name,
+            offset,
+            offsets,
+            sizes: chunks.iter().map(|chunk| chunk.size).collect(),
+            checksums: chunks
+                .iter()
+                .map(|chunk| base64::encode(&chunk.digest))
+                .collect(),
+            dedup: find_duplicates(chunks),
+        }
+    }
+}
+
+/// Blockmap with explicit chunk offsets, the size and SHA-512 of the input,
+/// and required chunker parameters. electron-updater only reads version 2,
+/// see `to_v2`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockmapV3 {
+    pub version: String,
+    pub size: usize,
+    #[serde(with = "crate::encoding::checksums::single")]
+    pub sha512: String,
+    pub parameters: ChunkerParameters,
+    pub files: Vec<BlockmapV3File>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl BlockmapV3 {
+    pub fn new(
+        parameters: ChunkerParameters,
+        size: usize,
+        sha512: &[u8],
+        files: Vec<BlockmapV3File>,
+    ) -> Self {
+        Self {
+            version: BLOCKMAP_V3_VERSION.to_string(),
+            size,
+            sha512: base64::encode(sha512),
+            parameters,
+            files,
+            signature: None,
+        }
+    }
+
+    /// Blockmap of a single input chunked with `options`, e.g. by
+    /// `chunk_file`.
+    pub fn from_chunks(options: &ChunkerOptions, stats: &Stats, chunks: &[Chunk]) -> Self {
+        Self::new(
+            options.into(),
+            stats.size,
+            &stats.sha512,
+            vec![BlockmapV3File::new("file".to_string(), 0, chunks)],
+        )
+    }
+
+    /// Drops everything that electron-updater doesn't read, including the
+    /// signature.
+    pub fn to_v2(&self) -> Blockmap {
+        Blockmap::new(
+            self.parameters.clone(),
+            self.files
+                .iter()
+                .map(|file| BlockmapFile {
+                    name: file.name.clone(),
+                    offset: file.offset,
+                    checksums: file.checksums.clone(),
+                    sizes: file.sizes.clone(),
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_bytes(&self, encoding: BlockmapEncoding) -> Result<Vec<u8>, BlockmapError> {
+        encode(self, encoding)
+    }
+}
+
+/// Blockmap of any supported version.
+#[derive(Debug, Clone)]
+pub enum VersionedBlockmap {
+    V2(Blockmap),
+    V3(BlockmapV3),
+}
+
+#[derive(Deserialize)]
+struct BlockmapVersion {
+    version: String,
+}
+
+impl VersionedBlockmap {
+    /// Decodes a blockmap of any version in any of the `BlockmapEncoding`s.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BlockmapError> {
+        let BlockmapVersion { version } = decode(data)?;
+        match version.as_str() {
+            BLOCKMAP_VERSION => Ok(VersionedBlockmap::V2(decode(data)?)),
+            BLOCKMAP_V3_VERSION => Ok(VersionedBlockmap::V3(decode(data)?)),
+            _ => Err(BlockmapError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Same as `from_bytes`, for data in any of the `BlockmapCompression`s.
+    pub fn from_compressed(data: &[u8]) -> Result<Self, BlockmapError> {
+        let (_, decompressed) =
+            BlockmapCompression::decompress(data).map_err(BlockmapError::Compression)?;
+        Self::from_bytes(&decompressed)
+    }
+
+    pub fn to_bytes(&self, encoding: BlockmapEncoding) -> Result<Vec<u8>, BlockmapError> {
+        match self {
+            VersionedBlockmap::V2(blockmap) => blockmap.to_bytes(encoding),
+            VersionedBlockmap::V3(blockmap) => blockmap.to_bytes(encoding),
+        }
+    }
+
+    pub fn into_v2(self) -> Blockmap {
+        match self {
+            VersionedBlockmap::V2(blockmap) => blockmap,
+            VersionedBlockmap::V3(blockmap) => blockmap.to_v2(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_serializes_parameters() {
+        let blockmap = Blockmap::new((&ChunkerOptions::default()).into(), vec![]);
+
+        let json = serde_json::to_string(&blockmap).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"version":"2","files":[],"parameters":{"algorithm":"rabin","windowSize":64,"#,
+                r#""minChunk":8192,"avgChunk":16384,"maxChunk":32768,"#,
+                r#""detectZipBoundary":false,"polynomial":"0xbfe6b8a5bf378d83","#,
+                r#""digest":"blake2b-18"}}"#
+            )
+        );
+
+        let legacy: Blockmap = serde_json::from_str(r#"{"version":"2","files":[]}"#).unwrap();
+        assert!(legacy.parameters.is_none());
+
+        let fastcdc = ChunkerParameters::from(&ChunkerOptions {
+            algorithm: ChunkingAlgorithm::FastCdc,
+            window_size: 48,
+            ..ChunkerOptions::default()
+        });
+        assert_eq!(fastcdc.window_size, 0);
+        assert_eq!(fastcdc.polynomial, "");
+    }
+
+    #[test]
+    fn it_checks_compatibility() {
+        let a = Blockmap::new((&ChunkerOptions::default()).into(), vec![]);
+        let b = Blockmap::new(
+            (&ChunkerOptions {
+                detect_zip_boundary: true,
+                ..ChunkerOptions::default()
+            })
+                .into(),
+            vec![],
+        );
+        let legacy: Blockmap = serde_json::from_str(r#"{"version":"2","files":[]}"#).unwrap();
+
+        assert_eq!(a.check_compatible(&a), Ok(()));
+        assert_eq!(
+            a.check_compatible(&b),
+            Err(IncompatibleParameters {
+                field: "detectZipBoundary"
+            })
+        );
+        assert_eq!(a.check_compatible(&legacy), Ok(()));
+
+        let salted = Blockmap::new(
+            (&ChunkerOptions {
+                chunk_digest_salt: b"product".to_vec(),
+                ..ChunkerOptions::default()
+            })
+                .into(),
+            vec![],
+        );
+        assert_eq!(
+            a.check_compatible(&salted),
+            Err(IncompatibleParameters { field: "salt" })
+        );
+    }
+
+    #[test]
+    fn it_diffs_blockmaps() {
+        let file = |checksums: &[&str], sizes: Vec<usize>| BlockmapFile {
+            name: "file".to_string(),
+            offset: 0,
+            checksums: checksums
+                .iter()
+                .map(|checksum| checksum.to_string())
+                .collect(),
+            sizes,
+        };
+        let old = Blockmap::new(
+            (&ChunkerOptions::default()).into(),
+            vec![file(&["AAAA", "BBBB", "CCCC"], vec![100, 200, 300])],
+        );
+        let new = Blockmap::new(
+            (&ChunkerOptions::default()).into(),
+            vec![file(
+                &["AAAA", "DDDD", "CCCC", "BBBB"],
+                vec![100, 400, 301, 200],
+            )],
+        );
+
+        assert_eq!(
+            new.diff(&old),
+            Ok(BlockmapDiff {
+                chunks: 4,
+                reused_chunks: 2,
+                size: 1001,
+                download_size: 701,
+            })
+        );
+
+        let fastcdc = Blockmap::new(
+            (&ChunkerOptions {
+                algorithm: ChunkingAlgorithm::FastCdc,
+                ..ChunkerOptions::default()
+            })
+                .into(),
+            old.files.clone(),
+        );
+        assert_eq!(
+            new.diff(&fastcdc),
+            Err(IncompatibleParameters { field: "algorithm" })
+        );
+    }
+
+    #[test]
+    fn it_writes_v3_blockmaps() {
+        let chunks = vec![
+            Chunk {
+                size: 10000,
+                digest: vec![1; 18],
+            },
+            Chunk {
+                size: 2345,
+                digest: vec![2; 18],
+            },
+        ];
+        let blockmap = BlockmapV3::new(
+            (&ChunkerOptions::default()).into(),
+            12345,
+            &[3; 64],
+            vec![BlockmapV3File::new("file".to_string(), 0, &chunks)],
+        );
+        assert_eq!(blockmap.files[0].offsets, vec![0, 10000]);
+        assert!(blockmap.files[0].dedup.is_empty());
+
+        let repeated = [chunks.clone(), chunks.clone()].concat();
+        let file = BlockmapV3File::new("file".to_string(), 0, &repeated);
+        assert_eq!(file.dedup, vec![(2, 0), (3, 1)]);
+
+        for encoding in [BlockmapEncoding::Json, BlockmapEncoding::Cbor] {
+            let data = blockmap.to_bytes(encoding).unwrap();
+            match VersionedBlockmap::from_bytes(&data).unwrap() {
+                VersionedBlockmap::V3(decoded) => assert_eq!(decoded, blockmap),
+                VersionedBlockmap::V2(_) => panic!("decoded as v2"),
+            }
+        }
+
+        // Downgraded blockmaps are the same as the ones written directly
+        let v2 = VersionedBlockmap::V3(blockmap).into_v2();
+        assert_eq!(
+            serde_json::to_string(&v2.files).unwrap(),
+            r#"[{"name":"file","offset":0,"checksums":["AQEBAQEBAQEBAQEBAQEBAQEB","AgICAgICAgICAgICAgICAgIC"],"sizes":[10000,2345]}]"#
+        );
+        let v2 = VersionedBlockmap::from_bytes(&v2.to_bytes(BlockmapEncoding::Json).unwrap());
+        assert!(matches!(v2, Ok(VersionedBlockmap::V2(_))));
+
+        assert!(matches!(
+            VersionedBlockmap::from_bytes(br#"{"version":"4"}"#),
+            Err(BlockmapError::UnsupportedVersion(version)) if version == "4"
+        ));
+    }
+}
@@ -0,0 +1,622 @@
+use serde::{Deserialize, Serialize};
+use std::collections::LinkedList;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::gear::GEAR;
+use crate::table::*;
+use crate::{CheckpointError, ChunkerOptions};
+
+const DEGREE: usize = 64;
+pub(crate) const ZIP_HEADER: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkingAlgorithm {
+    /// Rabin fingerprint over a `window_size` window
+    #[default]
+    Rabin,
+    /// Gear hash with normalized chunking (FastCDC)
+    FastCdc,
+}
+
+impl ChunkingAlgorithm {
+    /// Normalization level used when `ChunkerOptions::normalization` is not
+    /// set.
+    pub fn default_normalization(&self) -> u32 {
+        match self {
+            ChunkingAlgorithm::Rabin => 0,
+            ChunkingAlgorithm::FastCdc => 2,
+        }
+    }
+}
+
+impl fmt::Display for ChunkingAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkingAlgorithm::Rabin => write!(f, "rabin"),
+            ChunkingAlgorithm::FastCdc => write!(f, "fastcdc"),
+        }
+    }
+}
+
+impl FromStr for ChunkingAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rabin" => Ok(ChunkingAlgorithm::Rabin),
+            "fastcdc" => Ok(ChunkingAlgorithm::FastCdc),
+            _ => Err(format!("Unknown chunking algorithm: {}", s)),
+        }
+    }
+}
+
+// Mask with `bits` most significant bits set. The high bits of the Gear hash
+// depend on more input bytes than the low ones.
+fn gear_mask(bits: u32) -> u64 {
+    if bits == 0 {
+        0
+    } else {
+        u64::MAX << (64 - bits.min(64))
+    }
+}
+
+/// State of the current chunk, see `BoundaryFinder::state`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BoundaryState {
+    hash: u64,
+    #[serde(with = "crate::encoding::bytes")]
+    pub(crate) window: Vec<u8>,
+    pub(crate) chunk_size: usize,
+    zip_header_offset: usize,
+}
+
+/// Finds chunk boundaries without hashing the chunks.
+///
+/// Produces exactly the same cut points as `Chunker` with the same options,
+/// but yields only the end offsets of the chunks.
+pub struct BoundaryFinder {
+    algorithm: ChunkingAlgorithm,
+    table: Table,
+    hash: u64,
+
+    // Masks used before and after `avg_chunk`. They differ only with
+    // normalized chunking.
+    small_mask: u64,
+    large_mask: u64,
+    window: Vec<u8>,
+    window_size: usize,
+    min_chunk: usize,
+    avg_chunk: usize,
+    max_chunk: usize,
+    detect_zip_boundary: bool,
+    chunk_size: usize,
+    zip_header_offset: usize,
+    total_size: usize,
+    boundaries: LinkedList<usize>,
+}
+
+impl BoundaryFinder {
+    pub fn new(options: &ChunkerOptions) -> Self {
+        let normalization = options.normalization_level();
+        let (small_mask, large_mask) = match options.algorithm {
+            ChunkingAlgorithm::Rabin => (
+                (options
+                    .avg_chunk
+                    .checked_shl(normalization)
+                    .unwrap_or(0)
+                    .max(1)
+                    - 1) as u64,
+                (options
+                    .avg_chunk
+                    .checked_shr(normalization)
+                    .unwrap_or(0)
+                    .max(1)
+                    - 1) as u64,
+            ),
+            ChunkingAlgorithm::FastCdc => {
+                let avg_bits = options.avg_chunk.trailing_zeros();
+                (
+                    gear_mask(avg_bits + normalization),
+                    gear_mask(avg_bits.saturating_sub(normalization)),
+                )
+            }
+        };
+
+        Self {
+            algorithm: options.algorithm,
+            table: Table::new(options.window_size),
+            hash: 0,
+            small_mask,
+            large_mask,
+            window: vec![0; options.window_size],
+            window_size: options.window_size,
+            min_chunk: options.min_chunk,
+            avg_chunk: options.avg_chunk,
+            max_chunk: options.max_chunk,
+            detect_zip_boundary: options.detect_zip_boundary,
+            chunk_size: 0,
+            zip_header_offset: 0,
+            total_size: 0,
+            boundaries: LinkedList::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut rest = data;
+        while let Some(end) = self.next_boundary(rest) {
+            self.total_size += end;
+            self.boundaries.push_back(self.total_size);
+            rest = &rest[end..];
+        }
+        self.total_size += rest.len();
+    }
+
+    /// Returns the total size of the input and emits the end of the trailing
+    /// chunk.
+    pub fn finalize_reset(&mut self) -> usize {
+        let total_size = self.total_size;
+        if self.chunk_size != 0 {
+            self.boundaries.push_back(total_size);
+        }
+
+        self.total_size = 0;
+        self.reset();
+
+        total_size
+    }
+
+    /// Consumes `data` until the end of the current chunk and returns the
+    /// number of consumed bytes, or `None` if there is no boundary in `data`.
+    pub(crate) fn next_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        match self.algorithm {
+            ChunkingAlgorithm::Rabin => self.next_rabin_boundary(data),
+            ChunkingAlgorithm::FastCdc => self.next_gear_boundary(data),
+        }
+    }
+
+    #[inline(always)]
+    fn detect_zip_header(&mut self, b: u8) -> bool {
+        if self.detect_zip_boundary && self.zip_header_offset < ZIP_HEADER.len() {
+            if ZIP_HEADER[self.zip_header_offset] == b {
+                self.zip_header_offset += 1;
+            } else {
+                self.zip_header_offset = 0;
+            }
+        }
+
+        self.zip_header_offset == ZIP_HEADER.len()
+    }
+
+    /// Feeds skipped bytes through the zip header detection and returns the
+    /// number of consumed bytes if the header was found.
+    fn scan_zip_header(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, &b) in data.iter().enumerate() {
+            if self.detect_zip_header(b) {
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    fn next_rabin_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        if self.detect_zip_boundary {
+            self.next_rabin_boundary_impl::<true>(data)
+        } else {
+            self.next_rabin_boundary_impl::<false>(data)
+        }
+    }
+
+    // `window` holds the last `window_size` hashed bytes (oldest first, zeroes
+    // at the start of the chunk). Within `data` the dropped byte is read
+    // directly from the input, so there is no per-byte bookkeeping of the
+    // window.
+    #[inline(always)]
+    fn next_rabin_boundary_impl<const DETECT_ZIP: bool>(&mut self, data: &[u8]) -> Option<usize> {
+        let window_size = self.window_size;
+
+        // Skip until we are `window_size` bytes behind minimum chunk size
+        let hash_start = self.min_chunk.saturating_sub(window_size);
+        let mut start = 0;
+        if self.chunk_size < hash_start {
+            start = (hash_start - self.chunk_size).min(data.len());
+            if DETECT_ZIP {
+                if let Some(end) = self.scan_zip_header(&data[..start]) {
+                    self.reset();
+                    return Some(end);
+                }
+            }
+            self.chunk_size += start;
+        }
+
+        let table = &self.table;
+        let min_chunk = self.min_chunk;
+        let avg_chunk = self.avg_chunk;
+        let max_chunk = self.max_chunk;
+        let small_mask = self.small_mask;
+        let large_mask = self.large_mask;
+
+        let mut hash = self.hash;
+        let mut chunk_size = self.chunk_size;
+        let mut zip_header_offset = self.zip_header_offset;
+
+        let hashed = &data[start..];
+        let warmup = hashed.len().min(window_size);
+        let dropped_bytes = self.window[..warmup]
+            .iter()
+            .chain(hashed[..hashed.len() - warmup].iter());
+
+        for (i, (&b, &dropped_byte)) in hashed.iter().zip(dropped_bytes).enumerate() {
+            chunk_size += 1;
+
+            let seen_zip_header = DETECT_ZIP && {
+                if zip_header_offset < ZIP_HEADER.len() {
+                    if ZIP_HEADER[zip_header_offset] == b {
+                        zip_header_offset += 1;
+                    } else {
+                        zip_header_offset = 0;
+                    }
+                }
+                zip_header_offset == ZIP_HEADER.len()
+            };
+
+            let shifted_byte = hash >> (DEGREE - 8 - 1);
+            hash = (hash << 8)
+                ^ b as u64
+                ^ table.drop[dropped_byte as usize]
+                ^ table.shift[shifted_byte as usize];
+
+            let mask = if chunk_size < avg_chunk {
+                small_mask
+            } else {
+                large_mask
+            };
+
+            if seen_zip_header
+                || (chunk_size >= min_chunk && (hash & mask) == mask)
+                || chunk_size >= max_chunk
+            {
+                self.reset();
+                return Some(start + i + 1);
+            }
+        }
+
+        self.hash = hash;
+        self.chunk_size = chunk_size;
+        self.zip_header_offset = zip_header_offset;
+
+        // Keep the last `window_size` hashed bytes for the next call
+        if hashed.len() >= window_size {
+            self.window
+                .copy_from_slice(&hashed[hashed.len() - window_size..]);
+        } else {
+            self.window.copy_within(hashed.len().., 0);
+            self.window[window_size - hashed.len()..].copy_from_slice(hashed);
+        }
+
+        None
+    }
+
+    fn next_gear_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, &b) in data.iter().enumerate() {
+            self.chunk_size += 1;
+
+            let seen_zip_header = self.detect_zip_header(b);
+
+            if self.chunk_size < self.min_chunk && !seen_zip_header {
+                continue;
+            }
+
+            self.hash = (self.hash << 1).wrapping_add(GEAR[b as usize]);
+
+            let mask = if self.chunk_size < self.avg_chunk {
+                self.small_mask
+            } else {
+                self.large_mask
+            };
+
+            if !(seen_zip_header || (self.hash & mask) == 0 || self.chunk_size >= self.max_chunk) {
+                continue;
+            }
+
+            self.reset();
+            return Some(i + 1);
+        }
+
+        None
+    }
+
+    /// State needed by `next_boundary` to continue the current chunk.
+    pub(crate) fn state(&self) -> BoundaryState {
+        BoundaryState {
+            hash: self.hash,
+            window: self.window.clone(),
+            chunk_size: self.chunk_size,
+            zip_header_offset: self.zip_header_offset,
+        }
+    }
+
+    /// `state` must come from a finder with the same options. Fails if it
+    /// can't be one of its states.
+    pub(crate) fn restore(&mut self, state: BoundaryState) -> Result<(), CheckpointError> {
+        let invalid = |message: &str| Err(CheckpointError::InvalidState(message.to_string()));
+        if state.window.len() != self.window_size {
+            return invalid("window size doesn't match the options");
+        }
+        // Chunks end on reaching either
+        if state.chunk_size >= self.max_chunk || state.zip_header_offset >= ZIP_HEADER.len() {
+            return invalid("unfinished chunk should have ended");
+        }
+
+        self.hash = state.hash;
+        self.window = state.window;
+        self.chunk_size = state.chunk_size;
+        self.zip_header_offset = state.zip_header_offset;
+        Ok(())
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.hash = 0;
+        self.chunk_size = 0;
+        self.zip_header_offset = 0;
+        self.window.fill(0);
+    }
+}
+
+impl Iterator for BoundaryFinder {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.boundaries.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_data, sample_data, xorshift};
+    use crate::{Chunk, Chunker, InvalidOptions, SizeHistogram};
+
+    #[test]
+    #[cfg(feature = "window_size")]
+    fn it_computes_rolling_hash() {
+        let mut finder = BoundaryFinder::new(&ChunkerOptions {
+            window_size: 16,
+            min_chunk: 0,
+            max_chunk: 1024 * 1024,
+
+            // Make sure we never chunk for this test
+            avg_chunk: 1024 * 1024,
+            ..ChunkerOptions::default()
+        });
+
+        for i in 0..1024u64 {
+            finder.update(&[(i & 0xff) as u8]);
+        }
+        let rolling_hash = finder.hash;
+
+        finder.reset();
+        for i in (1024 - 16)..1024u64 {
+            finder.update(&[(i & 0xff) as u8]);
+        }
+        let non_rolling_hash = finder.hash;
+        assert_eq!(rolling_hash, non_rolling_hash);
+
+        assert_eq!(rolling_hash, 1976718474515856107);
+    }
+
+    // Straightforward byte-by-byte version of the Rabin chunker
+    fn reference_boundaries(options: &ChunkerOptions, data: &[u8]) -> Vec<usize> {
+        let table = Table::new(options.window_size);
+        let mask = (options.avg_chunk - 1) as u64;
+
+        let mut boundaries = Vec::new();
+        let mut window = vec![0; options.window_size];
+        let mut window_offset = 0;
+        let mut hash = 0u64;
+        let mut chunk_size = 0;
+        let mut zip_header_offset = 0;
+        for (i, &b) in data.iter().enumerate() {
+            chunk_size += 1;
+
+            if options.detect_zip_boundary && zip_header_offset < ZIP_HEADER.len() {
+                if ZIP_HEADER[zip_header_offset] == b {
+                    zip_header_offset += 1;
+                } else {
+                    zip_header_offset = 0;
+                }
+            }
+            let seen_zip_header = zip_header_offset == ZIP_HEADER.len();
+
+            if chunk_size + options.window_size <= options.min_chunk && !seen_zip_header {
+                continue;
+            }
+
+            let dropped_byte = window[window_offset] as usize;
+            let shifted_byte = hash >> (DEGREE - 8 - 1);
+            window[window_offset] = b;
+            window_offset = (window_offset + 1) % options.window_size;
+
+            hash <<= 8;
+            hash ^= b as u64;
+            hash ^= table.drop[dropped_byte];
+            hash ^= table.shift[shifted_byte as usize];
+
+            if seen_zip_header
+                || (chunk_size >= options.min_chunk && (hash & mask) == mask)
+                || chunk_size >= options.max_chunk
+            {
+                boundaries.push(i + 1);
+                hash = 0;
+                chunk_size = 0;
+                zip_header_offset = 0;
+                window.fill(0);
+            }
+        }
+        if chunk_size != 0 {
+            boundaries.push(data.len());
+        }
+        boundaries
+    }
+
+    #[test]
+    fn it_matches_reference_implementation() {
+        let mut next = xorshift(0x9e3779b97f4a7c15);
+
+        let mut data = random_data(0x2545f4914f6cdd1d, 1024 * 1024);
+        for _ in 0..64 {
+            let offset = (next() as usize) % (data.len() - ZIP_HEADER.len());
+            data[offset..offset + ZIP_HEADER.len()].copy_from_slice(&ZIP_HEADER);
+        }
+
+        for detect_zip_boundary in [false, true] {
+            for min_chunk in [0, 32, 8 * 1024] {
+                let options = ChunkerOptions {
+                    min_chunk,
+                    detect_zip_boundary,
+                    ..ChunkerOptions::default()
+                };
+                let expected = reference_boundaries(&options, &data);
+
+                let mut finder = BoundaryFinder::new(&options);
+                let mut offset = 0;
+                while offset < data.len() {
+                    let size = ((next() % 3000) as usize).min(data.len() - offset);
+                    finder.update(&data[offset..offset + size]);
+                    offset += size;
+                }
+                finder.finalize_reset();
+
+                assert_eq!(finder.collect::<Vec<usize>>(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn it_matches_chunker() {
+        let options = ChunkerOptions::default();
+        let data = sample_data(256 * 1024);
+
+        let mut finder = BoundaryFinder::new(&options);
+        let mut chunker = Chunker::new(options);
+        for piece in data.chunks(1000) {
+            finder.update(piece);
+            chunker.update(piece);
+        }
+        assert_eq!(finder.finalize_reset(), data.len());
+        chunker.finalize_reset();
+
+        let boundaries: Vec<usize> = finder.collect();
+        let chunks: Vec<Chunk> = chunker.collect();
+        assert!(chunks.len() > 1);
+        assert_eq!(boundaries.len(), chunks.len());
+
+        let mut offset = 0;
+        for (boundary, chunk) in boundaries.iter().zip(chunks.iter()) {
+            offset += chunk.size;
+            assert_eq!(*boundary, offset);
+        }
+    }
+
+    #[test]
+    fn it_normalizes_rabin_chunks() {
+        let data = random_data(0x2545f4914f6cdd1d, 4 * 1024 * 1024);
+
+        let histogram = |normalization| {
+            let options = ChunkerOptions {
+                normalization: Some(normalization),
+                ..ChunkerOptions::default()
+            };
+            let mut finder = BoundaryFinder::new(&options);
+            finder.update(&data);
+            finder.finalize_reset();
+
+            let mut histogram = SizeHistogram::new(&options);
+            let mut start = 0;
+            for end in finder {
+                histogram.add(end - start);
+                start = end;
+            }
+            histogram
+        };
+
+        let plain = histogram(0);
+        let normalized = histogram(2);
+        assert!(normalized.max_chunk_cuts * 2 < plain.max_chunk_cuts);
+
+        // Fewer chunks right after `min_chunk`
+        assert!(normalized.buckets[4] < plain.buckets[4]);
+    }
+
+    #[test]
+    fn it_limits_normalization() {
+        for (algorithm, max_normalization) in [
+            (ChunkingAlgorithm::Rabin, 49),
+            (ChunkingAlgorithm::FastCdc, 50),
+        ] {
+            let options = ChunkerOptions {
+                algorithm,
+                ..ChunkerOptions::default()
+            };
+            assert_eq!(options.max_normalization(), max_normalization);
+
+            for level in [max_normalization, max_normalization + 1, 64, u32::MAX] {
+                let options = ChunkerOptions {
+                    normalization: Some(level),
+                    ..options.clone()
+                };
+                let expected = if level == max_normalization {
+                    Ok(())
+                } else {
+                    Err(InvalidOptions::Normalization(level))
+                };
+                assert_eq!(options.validate(), expected);
+                assert_eq!(options.normalization_level(), max_normalization);
+
+                // Out of range levels are clamped instead of overflowing
+                let mut finder = BoundaryFinder::new(&options);
+                finder.update(&[0x55; 100 * 1024]);
+                assert_eq!(finder.finalize_reset(), 100 * 1024);
+            }
+        }
+    }
+
+    fn gear_boundaries(data: &[u8]) -> Vec<usize> {
+        let mut finder = BoundaryFinder::new(&ChunkerOptions {
+            algorithm: ChunkingAlgorithm::FastCdc,
+            ..ChunkerOptions::default()
+        });
+        finder.update(data);
+        finder.finalize_reset();
+        finder.collect()
+    }
+
+    #[test]
+    fn it_chunks_with_fastcdc() {
+        let options = ChunkerOptions::default();
+        let data = sample_data(1024 * 1024);
+
+        let boundaries = gear_boundaries(&data);
+        assert_eq!(boundaries.len(), 59);
+        assert_eq!(*boundaries.last().unwrap(), data.len());
+
+        let mut start = 0;
+        for &end in &boundaries[..boundaries.len() - 1] {
+            assert!(end - start >= options.min_chunk);
+            assert!(end - start <= options.max_chunk);
+            start = end;
+        }
+
+        // Boundaries resynchronize after an insertion
+        let mut shifted = vec![0xaa; 100];
+        shifted.extend_from_slice(&data);
+        let shifted_boundaries = gear_boundaries(&shifted);
+
+        let common = boundaries
+            .iter()
+            .filter(|end| shifted_boundaries.contains(&(*end + 100)))
+            .count();
+        assert!(common >= boundaries.len() - 2);
+    }
+}
@@ -0,0 +1,179 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::io;
+
+use crate::{Chunk, ChunkerOptions};
+
+/// Size of casync chunk IDs, which are SHA-256 digests of the chunks.
+pub const CAIBX_ID_SIZE: usize = 32;
+
+const CA_FORMAT_INDEX: u64 = 0x96824d9c7b129ff9;
+const CA_FORMAT_TABLE: u64 = 0xe75b9e112f17417d;
+const CA_FORMAT_TABLE_TAIL_MARKER: u64 = 0x4b4f050e5549ecd1;
+
+const INDEX_HEADER_SIZE: u64 = 48;
+const TABLE_HEADER_SIZE: u64 = 16;
+const TABLE_ITEM_SIZE: u64 = 8 + CAIBX_ID_SIZE as u64;
+const TABLE_TAIL_SIZE: u64 = 40;
+
+/// Builds a casync blob index (`.caibx`) from `chunks`, so that the file can
+/// be served from a casync or desync chunk store.
+///
+/// Chunk digests must be SHA-256 (`ChunkDigestType::Sha256` without a key or
+/// salt). The feature flags are left empty, which means SHA-256 chunk IDs and
+/// no compression requirements for the store.
+///
+/// # Panics
+///
+/// Panics if a chunk digest is not `CAIBX_ID_SIZE` bytes long.
+pub fn caibx_index(options: &ChunkerOptions, chunks: &[Chunk]) -> Vec<u8> {
+    let table_size = TABLE_HEADER_SIZE + TABLE_ITEM_SIZE * chunks.len() as u64 + TABLE_TAIL_SIZE;
+    let mut data = Vec::with_capacity((INDEX_HEADER_SIZE + table_size) as usize);
+
+    // Writes to a `Vec` can't fail
+    let mut write = |value: u64| data.write_u64::<LittleEndian>(value).unwrap();
+
+    write(INDEX_HEADER_SIZE);
+    write(CA_FORMAT_INDEX);
+    write(0);
+    write(options.min_chunk as u64);
+    write(options.avg_chunk as u64);
+    write(options.max_chunk as u64);
+
+    // The table size isn't known to streaming writers, so casync always
+    // stores it in the tail
+    write(u64::MAX);
+    write(CA_FORMAT_TABLE);
+
+    let mut offset = 0u64;
+    for chunk in chunks {
+        assert_eq!(
+            chunk.digest.len(),
+            CAIBX_ID_SIZE,
+            "casync chunk IDs must be SHA-256"
+        );
+
+        offset += chunk.size as u64;
+        data.write_u64::<LittleEndian>(offset).unwrap();
+        data.extend_from_slice(&chunk.digest);
+    }
+
+    let mut write = |value: u64| data.write_u64::<LittleEndian>(value).unwrap();
+    write(0);
+    write(0);
+    write(INDEX_HEADER_SIZE);
+    write(table_size);
+    write(CA_FORMAT_TABLE_TAIL_MARKER);
+
+    data
+}
+
+/// Chunk size parameters and chunks of a `.caibx` index.
+pub(crate) struct CaibxIndex {
+    pub min_chunk: usize,
+    pub avg_chunk: usize,
+    pub max_chunk: usize,
+    pub chunks: Vec<Chunk>,
+}
+
+pub(crate) fn is_caibx_index(data: &[u8]) -> bool {
+    data.len() >= 16
+        && LittleEndian::read_u64(&data[0..8]) == INDEX_HEADER_SIZE
+        && LittleEndian::read_u64(&data[8..16]) == CA_FORMAT_INDEX
+}
+
+/// Parses an index written by `caibx_index`, casync or desync.
+pub(crate) fn parse_caibx_index(data: &[u8]) -> io::Result<CaibxIndex> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let table_start = (INDEX_HEADER_SIZE + TABLE_HEADER_SIZE) as usize;
+    if !is_caibx_index(data) || data.len() < table_start + TABLE_TAIL_SIZE as usize {
+        return Err(invalid("not a casync index"));
+    }
+    let word = |offset: usize| LittleEndian::read_u64(&data[offset..offset + 8]);
+    if word(48) != u64::MAX || word(56) != CA_FORMAT_TABLE {
+        return Err(invalid("casync index has no table"));
+    }
+    let tail = data.len() - TABLE_TAIL_SIZE as usize;
+    if word(tail + 32) != CA_FORMAT_TABLE_TAIL_MARKER
+        || !(tail - table_start).is_multiple_of(TABLE_ITEM_SIZE as usize)
+    {
+        return Err(invalid("casync index table is truncated"));
+    }
+
+    let mut chunks = Vec::new();
+    let mut offset = 0u64;
+    for item in data[table_start..tail].chunks_exact(TABLE_ITEM_SIZE as usize) {
+        let end = LittleEndian::read_u64(&item[..8]);
+        if end <= offset {
+            return Err(invalid("casync index offsets are not increasing"));
+        }
+        chunks.push(Chunk {
+            size: (end - offset) as usize,
+            digest: item[8..].to_vec(),
+        });
+        offset = end;
+    }
+
+    Ok(CaibxIndex {
+        min_chunk: word(24) as usize,
+        avg_chunk: word(32) as usize,
+        max_chunk: word(40) as usize,
+        chunks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn it_builds_blob_index() {
+        let chunks = vec![
+            Chunk {
+                size: 10000,
+                digest: Sha256::digest(b"first").to_vec(),
+            },
+            Chunk {
+                size: 2345,
+                digest: Sha256::digest(b"second").to_vec(),
+            },
+        ];
+        let index = caibx_index(&ChunkerOptions::default(), &chunks);
+        assert_eq!(index.len(), 48 + 16 + 2 * 40 + 40);
+
+        let mut words = &index[..];
+        let mut read = || words.read_u64::<LittleEndian>().unwrap();
+        assert_eq!(read(), 48);
+        assert_eq!(read(), CA_FORMAT_INDEX);
+        assert_eq!(read(), 0);
+        assert_eq!(read(), 8 * 1024);
+        assert_eq!(read(), 16 * 1024);
+        assert_eq!(read(), 32 * 1024);
+        assert_eq!(read(), u64::MAX);
+        assert_eq!(read(), CA_FORMAT_TABLE);
+
+        // Items store the end offset of every chunk
+        assert_eq!(&index[64..72], &10000u64.to_le_bytes());
+        assert_eq!(&index[72..104], chunks[0].digest.as_slice());
+        assert_eq!(&index[104..112], &12345u64.to_le_bytes());
+        assert_eq!(&index[112..144], chunks[1].digest.as_slice());
+
+        let mut tail = &index[144..];
+        let mut read = || tail.read_u64::<LittleEndian>().unwrap();
+        assert_eq!(read(), 0);
+        assert_eq!(read(), 0);
+        assert_eq!(read(), 48);
+        assert_eq!(read(), 16 + 2 * 40 + 40);
+        assert_eq!(read(), CA_FORMAT_TABLE_TAIL_MARKER);
+
+        let parsed = parse_caibx_index(&index).unwrap();
+        assert_eq!(parsed.avg_chunk, 16 * 1024);
+        assert_eq!(parsed.chunks.len(), 2);
+        assert_eq!(parsed.chunks[1].size, 2345);
+        assert_eq!(parsed.chunks[1].digest, chunks[1].digest);
+
+        assert!(parse_caibx_index(&index[..index.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::boundary::BoundaryState;
+use crate::{
+    Chunk, ChunkerOptions, ChunkerParameters, FileDigestType, IncompatibleParameters,
+    InvalidOptions, ResumableChunker, ResumableSha512,
+};
+
+/// Saved state of a `ResumableChunker`, see `ResumableChunker::checkpoint`.
+///
+/// Holds the bytes of the unfinished chunk (less than `max_chunk`) and the
+/// chunks that were not yet taken from the iterator.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkerState {
+    parameters: ChunkerParameters,
+    boundary: BoundaryState,
+    #[serde(with = "crate::encoding::bytes")]
+    pending: Vec<u8>,
+    digest: ResumableSha512,
+    total_size: usize,
+    chunks: Vec<Chunk>,
+}
+
+impl ChunkerState {
+    /// Encodes the state as CBOR, with the unfinished chunk and the digests
+    /// as byte strings.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(self, &mut data).expect("CBOR serialization");
+        data
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        ciborium::de::from_reader(bytes)
+            .map_err(|err| CheckpointError::InvalidState(err.to_string()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CheckpointError {
+    /// State of additional file digests can't be saved
+    UnsupportedDigest(FileDigestType),
+    /// State was saved by a chunker with different options
+    IncompatibleParameters(IncompatibleParameters),
+    /// Options are rejected by `ChunkerOptions::validate`
+    InvalidOptions(InvalidOptions),
+    /// State is corrupt, e.g. it was only partially written
+    InvalidState(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::UnsupportedDigest(digest_type) => {
+                write!(f, "can't checkpoint `{}` file digest", digest_type)
+            }
+            CheckpointError::IncompatibleParameters(err) => write!(f, "{}", err),
+            CheckpointError::InvalidOptions(err) => write!(f, "{}", err),
+            CheckpointError::InvalidState(message) => {
+                write!(f, "invalid chunker state: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl ResumableChunker {
+    /// Saves the state of the chunker. Fails if additional file digests
+    /// were requested.
+    pub fn checkpoint(&self) -> Result<ChunkerState, CheckpointError> {
+        if let Some((digest_type, _)) = self.file_digests.first() {
+            return Err(CheckpointError::UnsupportedDigest(*digest_type));
+        }
+
+        Ok(ChunkerState {
+            parameters: self.parameters.clone(),
+            boundary: self.boundary_finder.state(),
+            pending: self.pending.clone(),
+            digest: self.digest.clone(),
+            total_size: self.total_size,
+            chunks: self.chunks.iter().cloned().collect(),
+        })
+    }
+
+    /// Restores a chunker saved with `checkpoint`. `options` must be the same
+    /// as the ones of the saved chunker, including the chunk digest key.
+    pub fn resume(options: ChunkerOptions, state: ChunkerState) -> Result<Self, CheckpointError> {
+        options
+            .validate()
+            .map_err(CheckpointError::InvalidOptions)?;
+        if let Some(&digest_type) = options.file_digests.first() {
+            return Err(CheckpointError::UnsupportedDigest(digest_type));
+        }
+        ChunkerParameters::from(&options)
+            .check_compatible(&state.parameters)
+            .map_err(CheckpointError::IncompatibleParameters)?;
+        if state.pending.len() != state.boundary.chunk_size {
+            return Err(CheckpointError::InvalidState(
+                "unfinished chunk doesn't match the boundary state".to_string(),
+            ));
+        }
+
+        let mut chunker = Self::new(options);
+        chunker.boundary_finder.restore(state.boundary)?;
+        chunker.pending = state.pending;
+        chunker.digest = state.digest;
+        chunker.total_size = state.total_size;
+        chunker.chunks = state.chunks.into_iter().collect();
+        Ok(chunker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{reference_chunks, sample_data};
+    use crate::ChunkingAlgorithm;
+
+    #[test]
+    fn it_resumes_from_checkpoint() {
+        let data = sample_data(256 * 1024);
+
+        for algorithm in [ChunkingAlgorithm::Rabin, ChunkingAlgorithm::FastCdc] {
+            let options = ChunkerOptions {
+                algorithm,
+                detect_zip_boundary: true,
+                ..ChunkerOptions::default()
+            };
+
+            let (expected_stats, expected) = reference_chunks(&data, options.clone());
+
+            for split in [1, 12345, 100_000] {
+                let mut chunker = ResumableChunker::new(options.clone());
+                chunker.update(&data[..split]);
+                let bytes = chunker.checkpoint().unwrap().to_bytes();
+                drop(chunker);
+
+                let state = ChunkerState::from_bytes(&bytes).unwrap();
+                let mut chunker = ResumableChunker::resume(options.clone(), state).unwrap();
+                chunker.update(&data[split..]);
+                let stats = chunker.finalize_reset();
+                let chunks: Vec<Chunk> = chunker.collect();
+
+                assert_eq!(stats.size, expected_stats.size);
+                assert_eq!(stats.sha512, expected_stats.sha512);
+                assert_eq!(chunks, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn it_rejects_unsupported_checkpoints() {
+        let options = ChunkerOptions {
+            file_digests: vec![FileDigestType::Blake3],
+            ..ChunkerOptions::default()
+        };
+        let chunker = ResumableChunker::new(options.clone());
+        assert_eq!(
+            chunker.checkpoint().unwrap_err(),
+            CheckpointError::UnsupportedDigest(FileDigestType::Blake3)
+        );
+
+        let state = ResumableChunker::new(ChunkerOptions::default())
+            .checkpoint()
+            .unwrap();
+        let other = ChunkerOptions {
+            min_chunk: 4096,
+            ..ChunkerOptions::default()
+        };
+        assert_eq!(
+            ResumableChunker::resume(other, state.clone()).err(),
+            Some(CheckpointError::IncompatibleParameters(
+                IncompatibleParameters { field: "minChunk" }
+            ))
+        );
+
+        // Options that `new` would panic on
+        let invalid = ChunkerOptions {
+            min_chunk: 64 * 1024,
+            ..ChunkerOptions::default()
+        };
+        assert_eq!(
+            ResumableChunker::resume(invalid, state).err(),
+            Some(CheckpointError::InvalidOptions(InvalidOptions::ChunkSizes))
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_states() {
+        let options = ChunkerOptions::default();
+        let mut chunker = ResumableChunker::new(options.clone());
+        chunker.update(&sample_data(12345));
+        let state = chunker.checkpoint().unwrap();
+
+        // Binary encoding doesn't spell out every byte as a number
+        let bytes = state.to_bytes();
+        assert!(bytes.len() < state.pending.len() + 1024);
+        assert!(matches!(
+            ChunkerState::from_bytes(&bytes[..bytes.len() / 2]),
+            Err(CheckpointError::InvalidState(_))
+        ));
+
+        let mut truncated_window = state.clone();
+        truncated_window.boundary.window.pop();
+        let mut long_pending = state.clone();
+        long_pending.pending.push(0);
+        let mut full_chunk = state.clone();
+        full_chunk.pending = vec![0; options.max_chunk];
+        full_chunk.boundary.chunk_size = options.max_chunk;
+
+        for invalid in [truncated_window, long_pending, full_chunk] {
+            assert!(matches!(
+                ResumableChunker::resume(options.clone(), invalid),
+                Err(CheckpointError::InvalidState(_))
+            ));
+        }
+        assert!(ResumableChunker::resume(options, state).is_ok());
+    }
+}
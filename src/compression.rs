@@ -0,0 +1,198 @@
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use crate::{Blockmap, BlockmapEncoding, BlockmapError};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// Compression of the written blockmap. electron-updater only reads gzip
+/// and deflate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockmapCompression {
+    #[default]
+    Gzip,
+    Deflate,
+    Zstd,
+    Brotli,
+}
+
+impl BlockmapCompression {
+    /// Best compression that doesn't need special decoder settings.
+    pub fn default_level(&self) -> u32 {
+        match self {
+            BlockmapCompression::Gzip | BlockmapCompression::Deflate => 9,
+            BlockmapCompression::Zstd => 19,
+            BlockmapCompression::Brotli => 11,
+        }
+    }
+
+    pub fn max_level(&self) -> u32 {
+        match self {
+            BlockmapCompression::Gzip | BlockmapCompression::Deflate => 9,
+            BlockmapCompression::Zstd => 22,
+            BlockmapCompression::Brotli => 11,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        if level > self.max_level() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} compression level must be at most {}",
+                    self,
+                    self.max_level()
+                ),
+            ));
+        }
+
+        match self {
+            BlockmapCompression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            BlockmapCompression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            BlockmapCompression::Zstd => zstd::encode_all(data, level as i32),
+            BlockmapCompression::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut compressed,
+                        BROTLI_BUFFER_SIZE,
+                        level,
+                        BROTLI_WINDOW_BITS,
+                    );
+                    encoder.write_all(data)?;
+                }
+                Ok(compressed)
+            }
+        }
+    }
+
+    /// Detects the compression of `data` and decompresses it.
+    ///
+    /// Gzip and zstd have magic numbers. Deflate and brotli streams don't, so
+    /// deflate is tried first and accepted only if it produces something that
+    /// looks like a blockmap.
+    pub fn decompress(data: &[u8]) -> io::Result<(Self, Vec<u8>)> {
+        let mut decompressed = Vec::new();
+
+        if data.starts_with(&GZIP_MAGIC) {
+            GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            return Ok((BlockmapCompression::Gzip, decompressed));
+        }
+        if data.starts_with(&ZSTD_MAGIC) {
+            return Ok((BlockmapCompression::Zstd, zstd::decode_all(data)?));
+        }
+
+        let inflated = DeflateDecoder::new(data).read_to_end(&mut decompressed);
+        if inflated.is_ok() && BlockmapEncoding::detect(&decompressed).is_some() {
+            return Ok((BlockmapCompression::Deflate, decompressed));
+        }
+
+        decompressed.clear();
+        brotli::Decompressor::new(data, BROTLI_BUFFER_SIZE)
+            .read_to_end(&mut decompressed)
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "unknown blockmap compression")
+            })?;
+        Ok((BlockmapCompression::Brotli, decompressed))
+    }
+}
+
+impl fmt::Display for BlockmapCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockmapCompression::Gzip => write!(f, "gzip"),
+            BlockmapCompression::Deflate => write!(f, "deflate"),
+            BlockmapCompression::Zstd => write!(f, "zstd"),
+            BlockmapCompression::Brotli => write!(f, "brotli"),
+        }
+    }
+}
+
+impl FromStr for BlockmapCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(BlockmapCompression::Gzip),
+            "deflate" => Ok(BlockmapCompression::Deflate),
+            "zstd" => Ok(BlockmapCompression::Zstd),
+            "brotli" => Ok(BlockmapCompression::Brotli),
+            _ => Err(format!("Unknown compression: {}", s)),
+        }
+    }
+}
+
+impl Blockmap {
+    /// Decodes a compressed blockmap in any of the `BlockmapCompression`s
+    /// and `BlockmapEncoding`s.
+    pub fn from_compressed(data: &[u8]) -> Result<Self, BlockmapError> {
+        let (_, decompressed) =
+            BlockmapCompression::decompress(data).map_err(BlockmapError::Compression)?;
+        Blockmap::from_bytes(&decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkerOptions;
+
+    const COMPRESSIONS: [BlockmapCompression; 4] = [
+        BlockmapCompression::Gzip,
+        BlockmapCompression::Deflate,
+        BlockmapCompression::Zstd,
+        BlockmapCompression::Brotli,
+    ];
+
+    #[test]
+    fn it_detects_compressions() {
+        let blockmap = Blockmap::new((&ChunkerOptions::default()).into(), vec![]);
+
+        for encoding in [BlockmapEncoding::Json, BlockmapEncoding::Cbor] {
+            let data = blockmap.to_bytes(encoding).unwrap();
+
+            for compression in COMPRESSIONS {
+                for level in [0, compression.default_level()] {
+                    let compressed = compression.compress(&data, level).unwrap();
+                    let (detected, decompressed) =
+                        BlockmapCompression::decompress(&compressed).unwrap();
+                    assert_eq!(detected, compression, "{} {}", encoding, level);
+                    assert_eq!(decompressed, data);
+
+                    let decoded = Blockmap::from_compressed(&compressed).unwrap();
+                    assert_eq!(decoded.parameters, blockmap.parameters);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_levels() {
+        for compression in COMPRESSIONS {
+            assert!(compression
+                .compress(b"{}", compression.max_level() + 1)
+                .is_err());
+        }
+
+        assert!(matches!(
+            Blockmap::from_compressed(b"not compressed"),
+            Err(BlockmapError::Compression(_))
+        ));
+    }
+}
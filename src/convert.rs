@@ -0,0 +1,365 @@
+use sha2::digest::DynDigest;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::caibx::{is_caibx_index, parse_caibx_index};
+use crate::file::for_each_buffer;
+use crate::zsync::parse_zsync_header;
+use crate::{
+    Blockmap, BlockmapFile, Chunk, ChunkDigestType, ChunkerOptions, ChunkerParameters,
+    RuntimeDigest, VersionedBlockmap, BLOCKMAP_VERSION,
+};
+
+/// Format of an existing chunk index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexFormat {
+    Blockmap,
+    Zsync,
+    Caibx,
+}
+
+impl fmt::Display for IndexFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexFormat::Blockmap => write!(f, "blockmap"),
+            IndexFormat::Zsync => write!(f, "zsync"),
+            IndexFormat::Caibx => write!(f, "caibx"),
+        }
+    }
+}
+
+impl FromStr for IndexFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blockmap" => Ok(IndexFormat::Blockmap),
+            "zsync" => Ok(IndexFormat::Zsync),
+            "caibx" => Ok(IndexFormat::Caibx),
+            _ => Err(format!("Unknown index format: {}", s)),
+        }
+    }
+}
+
+/// Chunk boundaries and digests of an existing blockmap, zsync or caibx
+/// index, used to convert it to the other formats.
+#[derive(Debug, Clone)]
+pub struct ChunkIndex {
+    pub format: IndexFormat,
+    pub sizes: Vec<usize>,
+
+    // Digests that can be reused as is. zsync checksums are truncated and
+    // can't be.
+    pub digests: Option<(ChunkDigestType, Vec<Vec<u8>>)>,
+
+    // Missing only for blockmaps generated by older versions or other tools.
+    // For zsync and caibx the `algorithm` is the name of the format.
+    pub parameters: Option<ChunkerParameters>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl ChunkIndex {
+    /// Parses a caibx index, a zsync file, or a compressed blockmap of any
+    /// version in any encoding.
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if is_caibx_index(data) {
+            Self::parse_caibx(data)
+        } else if data.starts_with(b"zsync: ") {
+            Self::parse_zsync(data)
+        } else {
+            let blockmap = VersionedBlockmap::from_compressed(data)
+                .map_err(|err| invalid_data(err.to_string()))?;
+            Self::from_blockmap(blockmap.into_v2())
+        }
+    }
+
+    fn from_blockmap(blockmap: Blockmap) -> io::Result<Self> {
+        let file = match <[BlockmapFile; 1]>::try_from(blockmap.files) {
+            Ok([file]) => file,
+            Err(files) => {
+                return Err(invalid_data(format!(
+                    "blockmap has {} files instead of one",
+                    files.len()
+                )))
+            }
+        };
+        if file.checksums.len() != file.sizes.len() {
+            return Err(invalid_data(
+                "blockmap has different number of checksums and sizes".to_string(),
+            ));
+        }
+
+        // electron-builder blockmaps don't have parameters and use the
+        // default digest
+        let digest_type = match &blockmap.parameters {
+            Some(parameters) => parameters.digest.parse().map_err(invalid_data)?,
+            None => ChunkDigestType::default(),
+        };
+        let digests = file
+            .checksums
+            .iter()
+            .map(base64::decode)
+            .collect::<Result<_, _>>()
+            .map_err(|err| invalid_data(err.to_string()))?;
+
+        Ok(Self {
+            format: IndexFormat::Blockmap,
+            sizes: file.sizes,
+            digests: Some((digest_type, digests)),
+            parameters: blockmap.parameters,
+        })
+    }
+
+    fn parse_caibx(data: &[u8]) -> io::Result<Self> {
+        let index = parse_caibx_index(data)?;
+        let options = ChunkerOptions {
+            min_chunk: index.min_chunk,
+            avg_chunk: index.avg_chunk,
+            max_chunk: index.max_chunk,
+            chunk_digest: ChunkDigestType::Sha256,
+            ..ChunkerOptions::default()
+        };
+
+        Ok(Self {
+            format: IndexFormat::Caibx,
+            sizes: index.chunks.iter().map(|chunk| chunk.size).collect(),
+            digests: Some((
+                ChunkDigestType::Sha256,
+                index.chunks.into_iter().map(|chunk| chunk.digest).collect(),
+            )),
+            parameters: Some(foreign_parameters(IndexFormat::Caibx, &options)),
+        })
+    }
+
+    fn parse_zsync(data: &[u8]) -> io::Result<Self> {
+        let (block_size, length) = parse_zsync_header(data)?;
+        // Every block has a checksum of at least one byte in the file, which
+        // bounds the `Length` header before allocating the sizes
+        let full_blocks = length / block_size as u64;
+        let rest = (length % block_size as u64) as usize;
+        if full_blocks + (rest != 0) as u64 > data.len() as u64 {
+            return Err(invalid_data(
+                "zsync length doesn't match its block checksums".to_string(),
+            ));
+        }
+        let mut sizes = vec![block_size; full_blocks as usize];
+        if rest != 0 {
+            sizes.push(rest);
+        }
+
+        let options = ChunkerOptions {
+            min_chunk: block_size,
+            avg_chunk: block_size,
+            max_chunk: block_size,
+            ..ChunkerOptions::default()
+        };
+
+        Ok(Self {
+            format: IndexFormat::Zsync,
+            sizes,
+            digests: None,
+            parameters: Some(foreign_parameters(IndexFormat::Zsync, &options)),
+        })
+    }
+
+    /// Chunks with the digests of the index, if they are `digest_type`.
+    pub fn chunks(&self, digest_type: ChunkDigestType) -> Option<Vec<Chunk>> {
+        match &self.digests {
+            Some((index_type, digests)) if *index_type == digest_type => Some(
+                self.sizes
+                    .iter()
+                    .zip(digests)
+                    .map(|(&size, digest)| Chunk {
+                        size,
+                        digest: digest.clone(),
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Chunks the original file at the boundaries of the index, computing
+    /// `digest_type` digests. Fails if the file size doesn't match.
+    pub fn chunk_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        digest_type: ChunkDigestType,
+    ) -> io::Result<Vec<Chunk>> {
+        let mut hasher = RuntimeDigest::for_chunks(digest_type, &[], &[]);
+        let mut chunks = Vec::with_capacity(self.sizes.len());
+        let mut filled = 0;
+        let mut is_longer = false;
+
+        for_each_buffer(path, |mut data| {
+            while !data.is_empty() {
+                let size = match self.sizes.get(chunks.len()) {
+                    Some(&size) => size,
+                    None => {
+                        is_longer = true;
+                        return;
+                    }
+                };
+
+                let end = (size - filled).min(data.len());
+                hasher.update(&data[..end]);
+                data = &data[end..];
+                filled += end;
+                if filled == size {
+                    chunks.push(Chunk {
+                        size,
+                        digest: hasher.finalize_reset().into_vec(),
+                    });
+                    filled = 0;
+                }
+            }
+        })?;
+
+        if is_longer || chunks.len() != self.sizes.len() {
+            return Err(invalid_data(format!(
+                "file size doesn't match the {} index",
+                self.format
+            )));
+        }
+        Ok(chunks)
+    }
+
+    /// Chunk size parameters for `caibx_index`. Blockmaps without parameters
+    /// use the sizes of their chunks.
+    pub fn caibx_options(&self) -> ChunkerOptions {
+        match &self.parameters {
+            Some(parameters) => ChunkerOptions {
+                min_chunk: parameters.min_chunk,
+                avg_chunk: parameters.avg_chunk,
+                max_chunk: parameters.max_chunk,
+                ..ChunkerOptions::default()
+            },
+            None => ChunkerOptions {
+                min_chunk: self.sizes.iter().copied().min().unwrap_or(0),
+                avg_chunk: self.sizes.iter().sum::<usize>() / self.sizes.len().max(1),
+                max_chunk: self.sizes.iter().copied().max().unwrap_or(0),
+                ..ChunkerOptions::default()
+            },
+        }
+    }
+
+    /// Blockmap of `chunks`, which were produced from this index with
+    /// `chunks` or `chunk_file`.
+    pub fn blockmap(&self, chunks: &[Chunk], digest_type: ChunkDigestType) -> Blockmap {
+        let parameters = self.parameters.as_ref().map(|parameters| {
+            if self.chunks(digest_type).is_some() {
+                return parameters.clone();
+            }
+
+            // Re-chunked without a key
+            ChunkerParameters {
+                digest: digest_type.to_string(),
+                keyed: false,
+                salt: None,
+                ..parameters.clone()
+            }
+        });
+
+        let file = BlockmapFile {
+            name: "file".to_string(),
+            offset: 0,
+            checksums: chunks
+                .iter()
+                .map(|chunk| base64::encode(&chunk.digest))
+                .collect(),
+            sizes: chunks.iter().map(|chunk| chunk.size).collect(),
+        };
+
+        Blockmap {
+            version: BLOCKMAP_VERSION.to_string(),
+            files: vec![file],
+            parameters,
+            signature: None,
+        }
+    }
+}
+
+// Parameters of indexes that weren't produced by our chunker. They are never
+// compatible with ours.
+fn foreign_parameters(format: IndexFormat, options: &ChunkerOptions) -> ChunkerParameters {
+    ChunkerParameters {
+        algorithm: format.to_string(),
+        window_size: 0,
+        normalization: 0,
+        polynomial: String::new(),
+        ..ChunkerParameters::from(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sample_data, TempFile};
+    use crate::{caibx_index, chunk_file, ZsyncBuilder};
+
+    #[test]
+    fn it_converts_indexes() {
+        let data = sample_data(256 * 1024);
+        let file = TempFile::new(&data);
+        let path = file.path();
+
+        let options = ChunkerOptions::default();
+        let (_, chunks) = chunk_file(path, options.clone()).unwrap();
+        let blockmap = Blockmap::new(
+            (&options).into(),
+            vec![BlockmapFile {
+                name: "file".to_string(),
+                offset: 0,
+                checksums: chunks
+                    .iter()
+                    .map(|chunk| base64::encode(&chunk.digest))
+                    .collect(),
+                sizes: chunks.iter().map(|chunk| chunk.size).collect(),
+            }],
+        );
+        let compressed = crate::BlockmapCompression::Gzip
+            .compress(
+                &blockmap.to_bytes(crate::BlockmapEncoding::Json).unwrap(),
+                9,
+            )
+            .unwrap();
+
+        // Blockmap digests can't be used in caibx, so the file is re-chunked
+        // at the same boundaries
+        let index = ChunkIndex::parse(&compressed).unwrap();
+        assert_eq!(index.format, IndexFormat::Blockmap);
+        assert!(index.chunks(ChunkDigestType::Sha256).is_none());
+        let sha256_chunks = index.chunk_file(path, ChunkDigestType::Sha256).unwrap();
+        assert_eq!(sha256_chunks.len(), chunks.len());
+        let caibx = caibx_index(&index.caibx_options(), &sha256_chunks);
+
+        // caibx digests are converted back directly
+        let index = ChunkIndex::parse(&caibx).unwrap();
+        assert_eq!(index.format, IndexFormat::Caibx);
+        let reused = index.chunks(ChunkDigestType::Sha256).unwrap();
+        assert_eq!(reused[3].digest, sha256_chunks[3].digest);
+        assert_eq!(reused[3].size, chunks[3].size);
+
+        let blake2b_chunks = index.chunk_file(path, ChunkDigestType::default()).unwrap();
+        assert_eq!(blake2b_chunks[3].digest, chunks[3].digest);
+        let converted = index.blockmap(&blake2b_chunks, ChunkDigestType::default());
+        assert_eq!(converted.files[0].checksums, blockmap.files[0].checksums);
+        assert_eq!(converted.parameters.unwrap().algorithm, "caibx");
+
+        let mut builder = ZsyncBuilder::new(2048);
+        builder.update(&data[..5000]);
+        let index = ChunkIndex::parse(&builder.finalize("file", "file").data).unwrap();
+        assert_eq!(index.sizes, vec![2048, 2048, 904]);
+        assert!(index.chunk_file(path, ChunkDigestType::Sha256).is_err());
+
+        // Length that has far more blocks than the file has checksums
+        let header = b"zsync: 0.6.2\nBlocksize: 1\nLength: 18446744073709551615\n\n";
+        let err = ChunkIndex::parse(header).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,88 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::Chunk;
+
+/// Finds chunks with the same size and digest as an earlier chunk. Returns
+/// `[index, index of the first occurrence]` pairs in the order of chunks.
+///
+/// An updater only has to fetch the chunks that are not in the list, and can
+/// copy the rest locally.
+pub fn find_duplicates(chunks: &[Chunk]) -> Vec<(usize, usize)> {
+    let mut first_occurrences = HashMap::with_capacity(chunks.len());
+    let mut duplicates = Vec::new();
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let first = *first_occurrences
+            .entry((chunk.size, chunk.digest.as_slice()))
+            .or_insert(index);
+        if first != index {
+            duplicates.push((index, first));
+        }
+    }
+
+    duplicates
+}
+
+/// Repeated content within one file, see `find_duplicates`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DedupStats {
+    pub chunks: usize,
+    pub unique_chunks: usize,
+
+    // Bytes that don't have to be downloaded
+    pub duplicate_size: usize,
+    pub unique_size: usize,
+}
+
+impl DedupStats {
+    pub fn new(chunks: &[Chunk], duplicates: &[(usize, usize)]) -> Self {
+        let total_size: usize = chunks.iter().map(|chunk| chunk.size).sum();
+        let duplicate_size = duplicates
+            .iter()
+            .map(|&(index, _)| chunks[index].size)
+            .sum();
+
+        Self {
+            chunks: chunks.len(),
+            unique_chunks: chunks.len() - duplicates.len(),
+            duplicate_size,
+            unique_size: total_size - duplicate_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_finds_duplicates() {
+        let chunk = |size: usize, byte: u8| Chunk {
+            size,
+            digest: vec![byte; 18],
+        };
+        let chunks = vec![
+            chunk(100, 1),
+            chunk(200, 2),
+            chunk(100, 1),
+            chunk(300, 1),
+            chunk(200, 2),
+            chunk(100, 1),
+        ];
+
+        let duplicates = find_duplicates(&chunks);
+        assert_eq!(duplicates, vec![(2, 0), (4, 1), (5, 0)]);
+
+        assert_eq!(
+            DedupStats::new(&chunks, &duplicates),
+            DedupStats {
+                chunks: 6,
+                unique_chunks: 3,
+                duplicate_size: 400,
+                unique_size: 600,
+            }
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use sha1::Sha1;
+use sha2::digest::{DynDigest, InvalidBufferSize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_BLAKE2B_SIZE: usize = 18;
+pub const MAX_BLAKE2B_SIZE: usize = blake2b_simd::OUTBYTES;
+pub const MAX_CHUNK_DIGEST_KEY_SIZE: usize = blake2b_simd::KEYBYTES;
+pub const MAX_CHUNK_DIGEST_SALT_SIZE: usize = blake2b_simd::SALTBYTES;
+
+/// Digest algorithm used for the chunk checksums.
+///
+/// The default (`Blake2b(18)`) is what electron-updater expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkDigestType {
+    /// Blake2b with the output length in bytes (1..=64)
+    Blake2b(usize),
+    Sha256,
+    Blake3,
+}
+
+impl Default for ChunkDigestType {
+    fn default() -> Self {
+        ChunkDigestType::Blake2b(DEFAULT_BLAKE2B_SIZE)
+    }
+}
+
+impl ChunkDigestType {
+    pub fn output_size(&self) -> usize {
+        match self {
+            ChunkDigestType::Blake2b(size) => *size,
+            ChunkDigestType::Sha256 => 32,
+            ChunkDigestType::Blake3 => blake3::OUT_LEN,
+        }
+    }
+}
+
+impl fmt::Display for ChunkDigestType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkDigestType::Blake2b(size) => write!(f, "blake2b-{}", size),
+            ChunkDigestType::Sha256 => write!(f, "sha256"),
+            ChunkDigestType::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl FromStr for ChunkDigestType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(ChunkDigestType::Sha256),
+            "blake3" => Ok(ChunkDigestType::Blake3),
+            "blake2b" => Ok(ChunkDigestType::default()),
+            _ => {
+                let size = s
+                    .strip_prefix("blake2b-")
+                    .and_then(|size| size.parse::<usize>().ok())
+                    .ok_or_else(|| format!("Unknown chunk digest: {}", s))?;
+                if size == 0 || size > MAX_BLAKE2B_SIZE {
+                    return Err(format!("Invalid Blake2b output size: {}", size));
+                }
+                Ok(ChunkDigestType::Blake2b(size))
+            }
+        }
+    }
+}
+
+/// Additional whole-file digest computed alongside SHA-512.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileDigestType {
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+impl fmt::Display for FileDigestType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileDigestType::Sha1 => write!(f, "sha1"),
+            FileDigestType::Sha256 => write!(f, "sha256"),
+            FileDigestType::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl FromStr for FileDigestType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(FileDigestType::Sha1),
+            "sha256" => Ok(FileDigestType::Sha256),
+            "blake3" => Ok(FileDigestType::Blake3),
+            _ => Err(format!("Unknown file digest: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Blake2b {
+    params: blake2b_simd::Params,
+    state: blake2b_simd::State,
+    size: usize,
+}
+
+#[derive(Clone)]
+enum Hasher {
+    Blake2b(Box<Blake2b>),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Digest selected at runtime with `ChunkDigestType` or `FileDigestType`.
+#[derive(Clone)]
+pub struct RuntimeDigest(Hasher);
+
+impl RuntimeDigest {
+    /// `key` and `salt` are only supported by Blake2b and are ignored when
+    /// empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics on the options rejected by `ChunkerOptions::validate`: a key or
+    /// salt with a digest other than Blake2b, a key or salt that is too long,
+    /// or a Blake2b output size outside of 1..=64.
+    pub fn for_chunks(digest_type: ChunkDigestType, key: &[u8], salt: &[u8]) -> Self {
+        assert!(
+            matches!(digest_type, ChunkDigestType::Blake2b(_))
+                || (key.is_empty() && salt.is_empty()),
+            "Chunk digest key and salt are supported only by Blake2b"
+        );
+
+        Self(match digest_type {
+            ChunkDigestType::Blake2b(size) => {
+                let mut params = blake2b_simd::Params::new();
+                params.hash_length(size).key(key).salt(salt);
+
+                Hasher::Blake2b(Box::new(Blake2b {
+                    state: params.to_state(),
+                    params,
+                    size,
+                }))
+            }
+            ChunkDigestType::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChunkDigestType::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        })
+    }
+
+    pub fn for_file(digest_type: FileDigestType) -> Self {
+        Self(match digest_type {
+            FileDigestType::Sha1 => Hasher::Sha1(Sha1::new()),
+            FileDigestType::Sha256 => Hasher::Sha256(Sha256::new()),
+            FileDigestType::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        })
+    }
+}
+
+impl DynDigest for RuntimeDigest {
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.0 {
+            Hasher::Blake2b(hasher) => {
+                hasher.state.update(data);
+            }
+            Hasher::Sha1(hasher) => Digest::update(hasher, data),
+            Hasher::Sha256(hasher) => Digest::update(hasher, data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finalize_into(mut self, buf: &mut [u8]) -> Result<(), InvalidBufferSize> {
+        self.finalize_into_reset(buf)
+    }
+
+    fn finalize_into_reset(&mut self, out: &mut [u8]) -> Result<(), InvalidBufferSize> {
+        if out.len() != self.output_size() {
+            return Err(InvalidBufferSize);
+        }
+
+        match &mut self.0 {
+            Hasher::Blake2b(hasher) => {
+                out.copy_from_slice(hasher.state.finalize().as_bytes());
+            }
+            Hasher::Sha1(hasher) => DynDigest::finalize_into_reset(hasher, out)?,
+            Hasher::Sha256(hasher) => DynDigest::finalize_into_reset(hasher, out)?,
+            Hasher::Blake3(hasher) => {
+                out.copy_from_slice(hasher.finalize().as_bytes());
+            }
+        }
+        self.reset();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        match &mut self.0 {
+            Hasher::Blake2b(hasher) => {
+                hasher.state = hasher.params.to_state();
+            }
+            Hasher::Sha1(hasher) => Digest::reset(hasher),
+            Hasher::Sha256(hasher) => Digest::reset(hasher),
+            Hasher::Blake3(hasher) => {
+                hasher.reset();
+            }
+        }
+    }
+
+    fn output_size(&self) -> usize {
+        match &self.0 {
+            Hasher::Blake2b(hasher) => hasher.size,
+            Hasher::Sha1(_) => <Sha1 as Digest>::output_size(),
+            Hasher::Sha256(_) => <Sha256 as Digest>::output_size(),
+            Hasher::Blake3(_) => blake3::OUT_LEN,
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn DynDigest> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blake2::Blake2b;
+
+    #[test]
+    fn it_matches_fixed_size_blake2b() {
+        let mut hasher = RuntimeDigest::for_chunks(ChunkDigestType::default(), &[], &[]);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+
+        let mut expected = Blake2b::<blake2::digest::consts::U18>::new();
+        Digest::update(&mut expected, b"hello world");
+        let expected = expected.finalize().to_vec();
+
+        assert_eq!(hasher.finalize_reset().to_vec(), expected);
+
+        // Hasher is reset after finalization
+        hasher.update(b"hello world");
+        assert_eq!(hasher.finalize_reset().to_vec(), expected);
+    }
+
+    #[test]
+    fn it_keys_blake2b() {
+        let mut plain = RuntimeDigest::for_chunks(ChunkDigestType::default(), &[], &[]);
+        let mut keyed = RuntimeDigest::for_chunks(ChunkDigestType::default(), b"secret", &[]);
+        let mut salted = RuntimeDigest::for_chunks(ChunkDigestType::default(), &[], b"product");
+
+        plain.update(b"hello world");
+        keyed.update(b"hello world");
+        salted.update(b"hello world");
+
+        let plain = plain.finalize_reset();
+        let keyed = keyed.finalize_reset();
+        let salted = salted.finalize_reset();
+        assert_ne!(plain, keyed);
+        assert_ne!(plain, salted);
+        assert_ne!(keyed, salted);
+        assert_eq!(keyed.len(), DEFAULT_BLAKE2B_SIZE);
+    }
+
+    #[test]
+    fn it_parses_digest_types() {
+        for digest in [
+            ChunkDigestType::Blake2b(18),
+            ChunkDigestType::Blake2b(32),
+            ChunkDigestType::Sha256,
+            ChunkDigestType::Blake3,
+        ] {
+            assert_eq!(digest.to_string().parse::<ChunkDigestType>(), Ok(digest));
+        }
+
+        assert!("blake2b-65".parse::<ChunkDigestType>().is_err());
+        assert!("md5".parse::<ChunkDigestType>().is_err());
+    }
+
+    #[test]
+    fn it_computes_file_digests() {
+        let mut hasher = RuntimeDigest::for_file(FileDigestType::Sha1);
+        hasher.update(b"abc");
+        assert_eq!(
+            hasher.finalize_reset().to_vec(),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+
+        // Hasher is reset after finalization
+        hasher.update(b"abc");
+        assert_eq!(hasher.finalize_reset()[0], 0xa9);
+    }
+}
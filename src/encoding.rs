@@ -0,0 +1,362 @@
+use serde::de::DeserializeOwned;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::{self, SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::Blockmap;
+
+/// Serialization of a `Blockmap`. electron-updater only reads JSON, the
+/// binary encodings store checksums as raw bytes instead of base64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockmapEncoding {
+    #[default]
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl BlockmapEncoding {
+    /// Guesses the encoding from the first byte: blockmaps are always maps
+    /// at the top level, and the three encodings mark them differently.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data.iter().find(|b| !b.is_ascii_whitespace())? {
+            b'{' => Some(BlockmapEncoding::Json),
+            0xa0..=0xbf => Some(BlockmapEncoding::Cbor),
+            0x80..=0x8f | 0xde | 0xdf => Some(BlockmapEncoding::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BlockmapEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockmapEncoding::Json => write!(f, "json"),
+            BlockmapEncoding::Cbor => write!(f, "cbor"),
+            BlockmapEncoding::MessagePack => write!(f, "msgpack"),
+        }
+    }
+}
+
+impl FromStr for BlockmapEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(BlockmapEncoding::Json),
+            "cbor" => Ok(BlockmapEncoding::Cbor),
+            "msgpack" => Ok(BlockmapEncoding::MessagePack),
+            _ => Err(format!("Unknown blockmap encoding: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockmapError {
+    /// Data doesn't look like any of the supported encodings
+    UnknownEncoding,
+    /// Blockmap can't be encoded or decoded with the given encoding
+    Invalid(BlockmapEncoding, String),
+    /// Compressed blockmap can't be decompressed
+    Compression(io::Error),
+    /// Blockmap version is not 2 or 3
+    UnsupportedVersion(String),
+    /// Blockmap has neither a detached nor an embedded signature
+    MissingSignature,
+    /// Signature is malformed or doesn't match the blockmap and the key
+    InvalidSignature,
+}
+
+impl fmt::Display for BlockmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockmapError::UnknownEncoding => write!(f, "unknown blockmap encoding"),
+            BlockmapError::Invalid(encoding, message) => {
+                write!(f, "invalid {} blockmap: {}", encoding, message)
+            }
+            BlockmapError::Compression(err) => write!(f, "invalid compressed blockmap: {}", err),
+            BlockmapError::UnsupportedVersion(version) => {
+                write!(f, "unsupported blockmap version {}", version)
+            }
+            BlockmapError::MissingSignature => write!(f, "blockmap is not signed"),
+            BlockmapError::InvalidSignature => write!(f, "invalid blockmap signature"),
+        }
+    }
+}
+
+impl std::error::Error for BlockmapError {}
+
+impl Blockmap {
+    pub fn to_bytes(&self, encoding: BlockmapEncoding) -> Result<Vec<u8>, BlockmapError> {
+        encode(self, encoding)
+    }
+
+    /// Decodes a blockmap in any of the `BlockmapEncoding`s.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, BlockmapError> {
+        decode(data)
+    }
+}
+
+pub(crate) fn encode<T: Serialize>(
+    value: &T,
+    encoding: BlockmapEncoding,
+) -> Result<Vec<u8>, BlockmapError> {
+    let invalid = |message: String| BlockmapError::Invalid(encoding, message);
+
+    match encoding {
+        BlockmapEncoding::Json => Ok(serde_json::to_vec(value).expect("JSON serialization")),
+        BlockmapEncoding::Cbor => {
+            let mut data = Vec::new();
+            ciborium::ser::into_writer(value, &mut data).map_err(|err| invalid(err.to_string()))?;
+            Ok(data)
+        }
+        // Structs are encoded as maps, since arrays don't work with skipped
+        // optional fields
+        BlockmapEncoding::MessagePack => {
+            rmp_serde::to_vec_named(value).map_err(|err| invalid(err.to_string()))
+        }
+    }
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, BlockmapError> {
+    let encoding = BlockmapEncoding::detect(data).ok_or(BlockmapError::UnknownEncoding)?;
+    let invalid = |message: String| BlockmapError::Invalid(encoding, message);
+
+    match encoding {
+        BlockmapEncoding::Json => {
+            serde_json::from_slice(data).map_err(|err| invalid(err.to_string()))
+        }
+        BlockmapEncoding::Cbor => {
+            ciborium::de::from_reader(data).map_err(|err| invalid(err.to_string()))
+        }
+        BlockmapEncoding::MessagePack => {
+            rmp_serde::from_slice(data).map_err(|err| invalid(err.to_string()))
+        }
+    }
+}
+
+/// Checksums are base64 strings in memory and in JSON, and raw bytes in
+/// binary encodings.
+pub(crate) mod checksums {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        checksums: &[String],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return checksums.serialize(serializer);
+        }
+
+        let mut seq = serializer.serialize_seq(Some(checksums.len()))?;
+        for checksum in checksums {
+            let bytes = base64::decode(checksum).map_err(ser::Error::custom)?;
+            seq.serialize_element(&RawChecksum(&bytes))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<String>, D::Error> {
+        if deserializer.is_human_readable() {
+            return Vec::<String>::deserialize(deserializer);
+        }
+
+        deserializer.deserialize_seq(ChecksumsVisitor)
+    }
+
+    /// Same for a single checksum.
+    pub(crate) mod single {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(checksum: &str, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+                return serializer.serialize_str(checksum);
+            }
+
+            let bytes = base64::decode(checksum).map_err(ser::Error::custom)?;
+            serializer.serialize_bytes(&bytes)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+            if deserializer.is_human_readable() {
+                return String::deserialize(deserializer);
+            }
+
+            Ok(EncodedChecksum::deserialize(deserializer)?.0)
+        }
+    }
+
+    struct RawChecksum<'a>(&'a [u8]);
+
+    impl Serialize for RawChecksum<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+
+    struct ChecksumsVisitor;
+
+    impl<'de> Visitor<'de> for ChecksumsVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a list of checksums")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut checksums = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(checksum) = seq.next_element::<EncodedChecksum>()? {
+                checksums.push(checksum.0);
+            }
+            Ok(checksums)
+        }
+    }
+
+    struct EncodedChecksum(String);
+
+    impl<'de> Deserialize<'de> for EncodedChecksum {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_bytes(ChecksumVisitor)
+        }
+    }
+
+    struct ChecksumVisitor;
+
+    impl<'de> Visitor<'de> for ChecksumVisitor {
+        type Value = EncodedChecksum;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "checksum bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(EncodedChecksum(base64::encode(bytes)))
+        }
+
+        // Written by encoders that don't distinguish byte strings
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(byte) = seq.next_element::<u8>()? {
+                bytes.push(byte);
+            }
+            Ok(EncodedChecksum(base64::encode(&bytes)))
+        }
+    }
+}
+
+/// Byte vectors as byte strings in binary encodings, instead of arrays of
+/// numbers. JSON still uses arrays.
+pub(crate) mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element::<u8>()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlockmapFile, ChunkerOptions};
+
+    fn blockmap() -> Blockmap {
+        Blockmap::new(
+            (&ChunkerOptions::default()).into(),
+            vec![BlockmapFile {
+                name: "file".to_string(),
+                offset: 0,
+                checksums: vec![
+                    "AAECAwQFBgcICQoLDA0ODxAR".to_string(),
+                    "/////////////////////wAA".to_string(),
+                ],
+                sizes: vec![12345, 16384],
+            }],
+        )
+    }
+
+    #[test]
+    fn it_round_trips_encodings() {
+        let blockmap = blockmap();
+        let json = blockmap.to_bytes(BlockmapEncoding::Json).unwrap();
+
+        for encoding in [
+            BlockmapEncoding::Json,
+            BlockmapEncoding::Cbor,
+            BlockmapEncoding::MessagePack,
+        ] {
+            let data = blockmap.to_bytes(encoding).unwrap();
+            assert_eq!(BlockmapEncoding::detect(&data), Some(encoding));
+
+            let decoded = Blockmap::from_bytes(&data).unwrap();
+            assert_eq!(decoded.files[0].checksums, blockmap.files[0].checksums);
+            assert_eq!(decoded.files[0].sizes, blockmap.files[0].sizes);
+            assert_eq!(decoded.parameters, blockmap.parameters);
+
+            // Converting back keeps the electron-builder JSON intact
+            assert_eq!(decoded.to_bytes(BlockmapEncoding::Json).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn it_stores_raw_checksums() {
+        let data = blockmap().to_bytes(BlockmapEncoding::Cbor).unwrap();
+
+        // 0x52: byte string of 18 bytes
+        let raw = [&[0x52][..], &(0..18).collect::<Vec<u8>>()].concat();
+        assert!(data.windows(raw.len()).any(|window| window == raw));
+    }
+
+    #[test]
+    fn it_rejects_invalid_blockmaps() {
+        assert!(matches!(
+            Blockmap::from_bytes(b"\x1f\x8b"),
+            Err(BlockmapError::UnknownEncoding)
+        ));
+        assert!(matches!(
+            Blockmap::from_bytes(b"{\"version\":"),
+            Err(BlockmapError::Invalid(BlockmapEncoding::Json, _))
+        ));
+
+        let mut blockmap = blockmap();
+        blockmap.files[0].checksums[0] = "not base64!".to_string();
+        assert!(matches!(
+            blockmap.to_bytes(BlockmapEncoding::MessagePack),
+            Err(BlockmapError::Invalid(BlockmapEncoding::MessagePack, _))
+        ));
+    }
+}
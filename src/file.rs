@@ -0,0 +1,120 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::{chunk_parallel, Chunk, Chunker, ChunkerOptions, PipelinedChunker, Stats};
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Chunks the file at `path`. The file is memory-mapped when possible and
+/// read in buffers otherwise (e.g. for pipes).
+pub fn chunk_file<P: AsRef<Path>>(
+    path: P,
+    options: ChunkerOptions,
+) -> io::Result<(Stats, Vec<Chunk>)> {
+    let mut chunker = Chunker::new(options);
+    for_each_buffer(path, |data| chunker.update(data))?;
+    Ok((chunker.finalize_reset(), chunker.collect()))
+}
+
+/// Same as `chunk_file`, but with up to `threads` threads. Uses
+/// `chunk_parallel` for memory-mapped files and `PipelinedChunker` otherwise.
+pub fn chunk_file_parallel<P: AsRef<Path>>(
+    path: P,
+    options: ChunkerOptions,
+    threads: usize,
+) -> io::Result<(Stats, Vec<Chunk>)> {
+    let mut file = File::open(path)?;
+
+    if let Some(map) = map_file(&file) {
+        return Ok(chunk_parallel(&map, &options, threads));
+    }
+
+    let mut chunker = PipelinedChunker::new(options, threads);
+    read_file(&mut file, |data| chunker.update(data))?;
+    Ok((chunker.finalize_reset(), chunker.collect()))
+}
+
+/// Passes the contents of the file at `path` to `update`, in one go if the
+/// file can be memory-mapped.
+pub(crate) fn for_each_buffer<P: AsRef<Path>>(
+    path: P,
+    mut update: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let mut file = File::open(path)?;
+
+    if let Some(map) = map_file(&file) {
+        update(&map);
+        return Ok(());
+    }
+
+    read_file(&mut file, update)
+}
+
+/// Calls `f` with the whole contents of the file at `path`. The file is read
+/// into memory if it can't be memory-mapped.
+pub(crate) fn with_file_data<P: AsRef<Path>, T>(
+    path: P,
+    f: impl FnOnce(&[u8]) -> T,
+) -> io::Result<T> {
+    let mut file = File::open(path)?;
+
+    if let Some(map) = map_file(&file) {
+        return Ok(f(&map));
+    }
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(f(&data))
+}
+
+fn map_file(file: &File) -> Option<Mmap> {
+    // Safety: the file must not be modified while it is being chunked, same
+    // as with buffered reads that would otherwise produce a torn result.
+    unsafe { Mmap::map(file) }.ok()
+}
+
+fn read_file(file: &mut File, mut update: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(bytes_read) => update(&buffer[..bytes_read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{reference_chunks, sample_data, TempFile};
+
+    #[test]
+    fn it_chunks_files() {
+        let data = sample_data(256 * 1024);
+        let file = TempFile::new(&data);
+        let path = file.path();
+
+        let (expected_stats, expected) = reference_chunks(&data, ChunkerOptions::default());
+
+        let results = [
+            chunk_file(path, ChunkerOptions::default()).unwrap(),
+            chunk_file_parallel(path, ChunkerOptions::default(), 2).unwrap(),
+        ];
+
+        // Buffered fallback
+        let mut size = 0;
+        read_file(&mut File::open(path).unwrap(), |data| size += data.len()).unwrap();
+        assert_eq!(size, data.len());
+
+        for (stats, chunks) in results {
+            assert_eq!(stats.size, expected_stats.size);
+            assert_eq!(stats.sha512, expected_stats.sha512);
+            assert_eq!(chunks, expected);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+// Random values for the Gear rolling hash, generated with SplitMix64 seeded
+// with zero. Changing them changes every FastCDC chunk boundary.
+pub const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_gear_table() {
+        // First SplitMix64 outputs for seed zero
+        assert_eq!(GEAR[0], 0xe220a8397b1dcdaf);
+        assert_eq!(GEAR[1], 0x6e789e6aa1b965f4);
+    }
+}
@@ -0,0 +1,61 @@
+use serde::Serialize;
+
+use crate::ChunkerOptions;
+
+const BUCKET_COUNT: usize = 16;
+
+/// Distribution of chunk sizes over `BUCKET_COUNT` equal buckets between zero
+/// and `max_chunk`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SizeHistogram {
+    pub bucket_size: usize,
+    pub buckets: Vec<usize>,
+
+    // Chunks that were forcefully cut at `max_chunk`
+    pub max_chunk_cuts: usize,
+
+    #[serde(skip)]
+    max_chunk: usize,
+}
+
+impl SizeHistogram {
+    pub fn new(options: &ChunkerOptions) -> Self {
+        Self {
+            bucket_size: options.max_chunk.div_ceil(BUCKET_COUNT).max(1),
+            buckets: vec![0; BUCKET_COUNT],
+            max_chunk_cuts: 0,
+            max_chunk: options.max_chunk,
+        }
+    }
+
+    pub fn add(&mut self, size: usize) {
+        let bucket = (size / self.bucket_size).min(BUCKET_COUNT - 1);
+        self.buckets[bucket] += 1;
+
+        if size == self.max_chunk {
+            self.max_chunk_cuts += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_counts_sizes() {
+        let mut histogram = SizeHistogram::new(&ChunkerOptions::default());
+        assert_eq!(histogram.bucket_size, 2048);
+
+        histogram.add(100);
+        histogram.add(8 * 1024);
+        histogram.add(8 * 1024 + 1);
+        histogram.add(32 * 1024);
+
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[4], 2);
+        assert_eq!(histogram.buckets[15], 1);
+        assert_eq!(histogram.max_chunk_cuts, 1);
+    }
+}
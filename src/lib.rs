@@ -1,179 +1,363 @@
-use blake2::{Blake2b, Digest};
+use serde::{Deserialize, Serialize};
+use sha2::digest::DynDigest;
 use sha2::Sha512;
 use std::collections::LinkedList;
 use std::default::Default;
-
+use std::fmt;
+use std::io::{self, Read, Write};
+
+mod appx;
+#[cfg(feature = "async")]
+mod async_io;
+mod blockmap;
+mod boundary;
+mod caibx;
+mod checkpoint;
+mod compression;
+mod convert;
+mod dedup;
+mod digest;
+mod encoding;
+mod file;
+mod gear;
+mod histogram;
+mod parallel;
+mod pipeline;
+mod sha512;
+mod signature;
 mod table;
 #[cfg(not(feature = "window_size"))]
 mod table_const;
 #[cfg(feature = "window_size")]
 mod table_gen;
-
+#[cfg(test)]
+mod test_util;
+mod validate;
+mod zsync;
+
+pub use crate::appx::{
+    appx_block_map, appx_block_map_file, appx_block_map_xml, AppxBlock, AppxFile, APPX_BLOCK_SIZE,
+};
+#[cfg(feature = "async")]
+pub use crate::async_io::chunk_async_reader;
+pub use crate::blockmap::*;
+pub use crate::boundary::{BoundaryFinder, ChunkingAlgorithm};
+pub use crate::caibx::{caibx_index, CAIBX_ID_SIZE};
+pub use crate::checkpoint::{CheckpointError, ChunkerState};
+pub use crate::compression::BlockmapCompression;
+pub use crate::convert::{ChunkIndex, IndexFormat};
+pub use crate::dedup::{find_duplicates, DedupStats};
+pub use crate::digest::{
+    ChunkDigestType, FileDigestType, RuntimeDigest, DEFAULT_BLAKE2B_SIZE, MAX_BLAKE2B_SIZE,
+    MAX_CHUNK_DIGEST_KEY_SIZE, MAX_CHUNK_DIGEST_SALT_SIZE,
+};
+pub use crate::encoding::{BlockmapEncoding, BlockmapError};
+pub use crate::file::{chunk_file, chunk_file_parallel};
+pub use crate::histogram::SizeHistogram;
+pub use crate::parallel::{chunk_parallel, find_boundaries};
+pub use crate::pipeline::PipelinedChunker;
+pub use crate::sha512::ResumableSha512;
 use crate::table::*;
+pub use crate::validate::BlockmapIssue;
+pub use crate::zsync::{
+    default_zsync_block_size, zsync_file, ZsyncBuilder, ZsyncControl, ZSYNC_VERSION,
+};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
 
-const DEGREE: usize = 64;
-const ZIP_HEADER: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChunkerOptions {
+    pub algorithm: ChunkingAlgorithm,
     pub window_size: usize,
     pub min_chunk: usize,
     pub avg_chunk: usize,
     pub max_chunk: usize,
+
+    // Normalized chunking level: the cut mask has this many more bits before
+    // `avg_chunk` and this many fewer after it. Defaults to the algorithm's
+    // `default_normalization()`.
+    pub normalization: Option<u32>,
+
     pub detect_zip_boundary: bool,
+    pub chunk_digest: ChunkDigestType,
+
+    // Secret key and public salt for Blake2b chunk digests. Only the salt is
+    // recorded in the blockmap.
+    pub chunk_digest_key: Vec<u8>,
+    pub chunk_digest_salt: Vec<u8>,
+
+    // Computed in addition to SHA-512
+    pub file_digests: Vec<FileDigestType>,
 }
 
 impl Default for ChunkerOptions {
     fn default() -> Self {
         Self {
+            algorithm: ChunkingAlgorithm::default(),
             window_size: DEFAULT_WINDOW_SIZE,
             min_chunk: 8 * 1024,
             avg_chunk: 16 * 1024,
             max_chunk: 32 * 1024,
+            normalization: None,
             detect_zip_boundary: false,
+            chunk_digest: ChunkDigestType::default(),
+            chunk_digest_key: Vec::new(),
+            chunk_digest_salt: Vec::new(),
+            file_digests: Vec::new(),
+        }
+    }
+}
+
+impl ChunkerOptions {
+    /// Normalization level that is used for chunking and recorded in the
+    /// blockmap, at most `max_normalization`.
+    pub fn normalization_level(&self) -> u32 {
+        self.normalization
+            .unwrap_or_else(|| self.algorithm.default_normalization())
+            .min(self.max_normalization())
+    }
+
+    /// Highest normalization level for `avg_chunk`: the cut mask before
+    /// `avg_chunk` must fit into the 64-bit hash.
+    pub fn max_normalization(&self) -> u32 {
+        match self.algorithm {
+            ChunkingAlgorithm::Rabin => (self.avg_chunk as u64).leading_zeros(),
+            ChunkingAlgorithm::FastCdc => 64 - (self.avg_chunk as u64).trailing_zeros().min(64),
+        }
+    }
+
+    /// Checks the options that would make `Chunker::new` panic, and the
+    /// normalization level.
+    pub fn validate(&self) -> Result<(), InvalidOptions> {
+        if self.avg_chunk == 0 || self.min_chunk > self.avg_chunk || self.avg_chunk > self.max_chunk
+        {
+            return Err(InvalidOptions::ChunkSizes);
+        }
+        match self.normalization {
+            Some(level) if level > self.max_normalization() => {
+                return Err(InvalidOptions::Normalization(level));
+            }
+            _ => {}
+        }
+
+        let is_blake2b = match self.chunk_digest {
+            ChunkDigestType::Blake2b(size) => {
+                if size == 0 || size > MAX_BLAKE2B_SIZE {
+                    return Err(InvalidOptions::DigestSize(size));
+                }
+                true
+            }
+            ChunkDigestType::Sha256 | ChunkDigestType::Blake3 => false,
+        };
+
+        let is_keyed = !self.chunk_digest_key.is_empty() || !self.chunk_digest_salt.is_empty();
+        if is_keyed && !is_blake2b {
+            return Err(InvalidOptions::KeyedDigest(self.chunk_digest));
+        }
+        if self.chunk_digest_key.len() > MAX_CHUNK_DIGEST_KEY_SIZE {
+            return Err(InvalidOptions::KeyTooLong);
+        }
+        if self.chunk_digest_salt.len() > MAX_CHUNK_DIGEST_SALT_SIZE {
+            return Err(InvalidOptions::SaltTooLong);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reason why `ChunkerOptions::validate` failed.
+#[derive(Debug, PartialEq)]
+pub enum InvalidOptions {
+    /// Chunk sizes are not `min_chunk <= avg_chunk <= max_chunk` with a
+    /// non-zero `avg_chunk`
+    ChunkSizes,
+    /// Normalization level is above `ChunkerOptions::max_normalization`
+    Normalization(u32),
+    /// Blake2b output size is not within 1..=64 bytes
+    DigestSize(usize),
+    /// Key or salt given for a chunk digest other than Blake2b
+    KeyedDigest(ChunkDigestType),
+    /// Key is longer than `MAX_CHUNK_DIGEST_KEY_SIZE`
+    KeyTooLong,
+    /// Salt is longer than `MAX_CHUNK_DIGEST_SALT_SIZE`
+    SaltTooLong,
+}
+
+impl fmt::Display for InvalidOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidOptions::ChunkSizes => write!(f, "chunk sizes must be min <= avg <= max"),
+            InvalidOptions::Normalization(level) => {
+                write!(f, "normalization level {} is too high", level)
+            }
+            InvalidOptions::DigestSize(size) => write!(f, "invalid Blake2b output size {}", size),
+            InvalidOptions::KeyedDigest(digest_type) => write!(
+                f,
+                "chunk digest key and salt require Blake2b, not `{}`",
+                digest_type
+            ),
+            InvalidOptions::KeyTooLong => write!(
+                f,
+                "chunk digest key is longer than {} bytes",
+                MAX_CHUNK_DIGEST_KEY_SIZE
+            ),
+            InvalidOptions::SaltTooLong => write!(
+                f,
+                "chunk digest salt is longer than {} bytes",
+                MAX_CHUNK_DIGEST_SALT_SIZE
+            ),
         }
     }
 }
 
-#[derive(Debug)]
+impl std::error::Error for InvalidOptions {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Chunk {
     pub size: usize,
+    #[serde(with = "crate::encoding::bytes")]
     pub digest: Vec<u8>,
 }
 
 pub struct Stats {
     pub size: usize,
+
+    // Output of the whole-file digest, which is SHA-512 unless a different one
+    // was given to `GenericChunker::with_digests`
     pub sha512: Vec<u8>,
+    pub digests: Vec<(FileDigestType, Vec<u8>)>,
+}
+
+impl Stats {
+    pub fn digest(&self, digest_type: FileDigestType) -> Option<&[u8]> {
+        self.digests
+            .iter()
+            .find(|(t, _)| *t == digest_type)
+            .map(|(_, digest)| digest.as_slice())
+    }
 }
 
-pub struct Chunker {
-    table: Table,
-    options: ChunkerOptions,
-    hash: u64,
-    hash_mask: u64,
-    window: Vec<u8>,
-    window_size: usize,
-    window_offset: usize,
-    chunk_size: usize,
-    chunk_digest: Blake2b<blake2::digest::consts::U18>,
-    digest: Sha512,
+/// Chunker with pluggable chunk (`C`) and whole-file (`F`) digests. Any
+/// RustCrypto hasher can be used for either of them.
+pub struct GenericChunker<C, F> {
+    boundary_finder: BoundaryFinder,
+    // Bytes of the current chunk. They are hashed only once the chunk ends,
+    // so that the chunker state can be saved without the chunk digest state.
+    pending: Vec<u8>,
+    chunk_digest: C,
+    digest: F,
+    file_digests: Vec<(FileDigestType, RuntimeDigest)>,
     total_size: usize,
-    zip_header_offset: usize,
     chunks: LinkedList<Chunk>,
+
+    // Saved with the chunker state to check the options on resume
+    parameters: ChunkerParameters,
 }
 
-impl Chunker {
+pub type Chunker = GenericChunker<RuntimeDigest, Sha512>;
+
+/// Chunker that can be saved with `checkpoint` and restored with `resume`.
+/// Produces the same chunks and stats as `Chunker`, but hashes the file
+/// with `ResumableSha512`.
+pub type ResumableChunker = GenericChunker<RuntimeDigest, ResumableSha512>;
+
+impl<F: DynDigest + Default> GenericChunker<RuntimeDigest, F> {
+    /// # Panics
+    ///
+    /// Panics if `options.validate()` fails.
     pub fn new(options: ChunkerOptions) -> Self {
-        let hash_mask = options.avg_chunk - 1;
+        if let Err(err) = options.validate() {
+            panic!("Invalid chunker options: {}", err);
+        }
+
+        let chunk_digest = RuntimeDigest::for_chunks(
+            options.chunk_digest,
+            &options.chunk_digest_key,
+            &options.chunk_digest_salt,
+        );
+
+        Self::with_digests(options, chunk_digest, F::default())
+    }
+
+    /// Chunks everything read from `reader`.
+    pub fn from_reader<R: Read>(
+        mut reader: R,
+        options: ChunkerOptions,
+    ) -> io::Result<(Stats, Vec<Chunk>)> {
+        let mut chunker = Self::new(options);
+        io::copy(&mut reader, &mut chunker)?;
+        Ok((chunker.finalize_reset(), chunker.collect()))
+    }
+}
 
+impl<C: DynDigest, F: DynDigest> GenericChunker<C, F> {
+    /// Note that `options.chunk_digest` and its key and salt are ignored in
+    /// favor of `chunk_digest`.
+    pub fn with_digests(options: ChunkerOptions, chunk_digest: C, digest: F) -> Self {
         Self {
-            table: Table::new(options.window_size),
-            hash: 0,
-            hash_mask: hash_mask as u64,
-            window: vec![0; options.window_size],
-            window_size: options.window_size,
-            window_offset: 0,
-            chunk_size: 0,
-            chunk_digest: Blake2b::new(),
-            digest: Sha512::new(),
+            boundary_finder: BoundaryFinder::new(&options),
+            pending: Vec::with_capacity(options.max_chunk),
+            chunk_digest,
+            digest,
+            file_digests: options
+                .file_digests
+                .iter()
+                .map(|&digest_type| (digest_type, RuntimeDigest::for_file(digest_type)))
+                .collect(),
             total_size: 0,
-            zip_header_offset: 0,
             chunks: LinkedList::new(),
-
-            options,
+            parameters: ChunkerParameters::from(&options),
         }
     }
 
     pub fn update(&mut self, data: &[u8]) {
-        let window_size = self.options.window_size;
-
-        let mut chunk_start = 0;
-
         self.digest.update(data);
+        for (_, hasher) in self.file_digests.iter_mut() {
+            hasher.update(data);
+        }
         self.total_size += data.len();
 
-        for i in 0..data.len() {
-            self.chunk_size += 1;
-
-            if self.options.detect_zip_boundary && self.zip_header_offset < ZIP_HEADER.len() {
-                let b = data[i];
-                if ZIP_HEADER[self.zip_header_offset] == b {
-                    self.zip_header_offset += 1;
-                } else {
-                    self.zip_header_offset = 0;
-                }
-            }
-
-            let seen_zip_header = self.zip_header_offset == ZIP_HEADER.len();
-
-            // Skip until we are `window_size`  bytes behind minimum chunk size
-            if self.chunk_size + self.window_size <= self.options.min_chunk && !seen_zip_header {
-                continue;
-            }
-
-            let b = data[i];
-            let dropped_byte = self.window[self.window_offset] as usize;
-            let shifted_byte = self.hash >> (DEGREE - 8 - 1);
-            self.window[self.window_offset] = b;
-            self.window_offset = (self.window_offset + 1) % window_size;
-
-            self.hash <<= 8;
-            self.hash ^= b as u64;
-            self.hash ^= self.table.drop[dropped_byte];
-            self.hash ^= self.table.shift[shifted_byte as usize];
-
-            if !(seen_zip_header
-                || (self.chunk_size >= self.options.min_chunk
-                    && (self.hash & self.hash_mask) == self.hash_mask)
-                || self.chunk_size >= self.options.max_chunk)
-            {
-                continue;
-            }
-
-            self.chunk_digest.update(&data[chunk_start..=i]);
+        let mut rest = data;
+        while let Some(end) = self.boundary_finder.next_boundary(rest) {
+            self.chunk_digest.update(&self.pending);
+            self.chunk_digest.update(&rest[..end]);
             self.chunks.push_back(Chunk {
-                size: self.chunk_size,
-                digest: self.chunk_digest.finalize_reset().to_vec(),
+                size: self.pending.len() + end,
+                digest: self.chunk_digest.finalize_reset().into_vec(),
             });
-            chunk_start = i + 1;
-            self.reset();
+            self.pending.clear();
+            rest = &rest[end..];
         }
 
-        if chunk_start < data.len() {
-            self.chunk_digest.update(&data[chunk_start..]);
-        }
+        self.pending.extend_from_slice(rest);
     }
 
     pub fn finalize_reset(&mut self) -> Stats {
         let total_size = self.total_size;
-        let chunk_size = self.chunk_size;
-        let digest = self.chunk_digest.finalize_reset();
-
         self.total_size = 0;
-        self.reset();
+        self.boundary_finder.reset();
 
-        if chunk_size != 0 {
+        if !self.pending.is_empty() {
+            self.chunk_digest.update(&self.pending);
             self.chunks.push_back(Chunk {
-                size: chunk_size,
-                digest: digest.to_vec(),
-            })
+                size: self.pending.len(),
+                digest: self.chunk_digest.finalize_reset().into_vec(),
+            });
+            self.pending.clear();
         }
 
         Stats {
             size: total_size,
-            sha512: self.digest.finalize_reset().to_vec(),
+            sha512: self.digest.finalize_reset().into_vec(),
+            digests: self
+                .file_digests
+                .iter_mut()
+                .map(|(digest_type, hasher)| (*digest_type, hasher.finalize_reset().into_vec()))
+                .collect(),
         }
     }
-
-    fn reset(&mut self) {
-        self.hash = 0;
-        self.chunk_size = 0;
-        self.zip_header_offset = 0;
-        self.window.fill(0);
-    }
 }
 
-impl Iterator for Chunker {
+impl<C, F> Iterator for GenericChunker<C, F> {
     type Item = Chunk;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -181,39 +365,26 @@ impl Iterator for Chunker {
     }
 }
 
+/// Feeds written data to `update`, e.g. with `io::copy`. Call
+/// `finalize_reset` once everything is written.
+impl<C: DynDigest, F: DynDigest> Write for GenericChunker<C, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate base64;
 
     use super::*;
-
-    #[test]
-    #[cfg(feature = "window_size")]
-    fn it_computes_rolling_hash() {
-        let mut chunker = Chunker::new(ChunkerOptions {
-            window_size: 16,
-            min_chunk: 0,
-            max_chunk: 1024 * 1024,
-
-            // Make sure we never chunk for this test
-            avg_chunk: 1024 * 1024,
-            detect_zip_boundary: false,
-        });
-
-        for i in 0..1024u64 {
-            chunker.update(&[(i & 0xff) as u8]);
-        }
-        let rolling_hash = chunker.hash;
-
-        chunker.reset();
-        for i in (1024 - 16)..1024u64 {
-            chunker.update(&[(i & 0xff) as u8]);
-        }
-        let non_rolling_hash = chunker.hash;
-        assert_eq!(rolling_hash, non_rolling_hash);
-
-        assert_eq!(rolling_hash, 1976718474515856107);
-    }
+    use crate::test_util::{reference_chunks, sample_data};
+    use sha2::Digest;
 
     #[test]
     fn it_computes_chunks() {
@@ -231,6 +402,120 @@ mod tests {
         assert_eq!(chunker.count(), 24);
     }
 
+    #[test]
+    fn it_computes_file_digests() {
+        let mut chunker = Chunker::new(ChunkerOptions {
+            file_digests: vec![FileDigestType::Sha256, FileDigestType::Blake3],
+            ..ChunkerOptions::default()
+        });
+
+        chunker.update(b"ab");
+        chunker.update(b"c");
+
+        let stats = chunker.finalize_reset();
+        assert_eq!(
+            stats.digest(FileDigestType::Sha256).unwrap()[..4],
+            [0xba, 0x78, 0x16, 0xbf]
+        );
+        assert_eq!(
+            stats.digest(FileDigestType::Blake3),
+            Some(&blake3::hash(b"abc").as_bytes()[..])
+        );
+        assert_eq!(stats.digest(FileDigestType::Sha1), None);
+    }
+
+    #[test]
+    fn it_uses_custom_digests() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        let mut chunker = GenericChunker::with_digests(
+            ChunkerOptions::default(),
+            sha2::Sha256::new(),
+            sha2::Sha256::new(),
+        );
+        chunker.update(&data);
+        let stats = chunker.finalize_reset();
+        let custom: Vec<Chunk> = chunker.collect();
+
+        let (expected_stats, expected) = reference_chunks(
+            &data,
+            ChunkerOptions {
+                chunk_digest: ChunkDigestType::Sha256,
+                file_digests: vec![FileDigestType::Sha256],
+                ..ChunkerOptions::default()
+            },
+        );
+
+        assert_eq!(
+            Some(stats.sha512.as_slice()),
+            expected_stats.digest(FileDigestType::Sha256)
+        );
+        assert_eq!(custom, expected);
+    }
+
+    #[test]
+    fn it_validates_options() {
+        assert_eq!(ChunkerOptions::default().validate(), Ok(()));
+
+        let invalid = [
+            (
+                ChunkDigestType::Blake2b(0),
+                vec![],
+                vec![],
+                InvalidOptions::DigestSize(0),
+            ),
+            (
+                ChunkDigestType::Blake2b(65),
+                vec![],
+                vec![],
+                InvalidOptions::DigestSize(65),
+            ),
+            (
+                ChunkDigestType::Sha256,
+                b"secret".to_vec(),
+                vec![],
+                InvalidOptions::KeyedDigest(ChunkDigestType::Sha256),
+            ),
+            (
+                ChunkDigestType::Blake3,
+                vec![],
+                b"product".to_vec(),
+                InvalidOptions::KeyedDigest(ChunkDigestType::Blake3),
+            ),
+            (
+                ChunkDigestType::default(),
+                vec![0; MAX_CHUNK_DIGEST_KEY_SIZE + 1],
+                vec![],
+                InvalidOptions::KeyTooLong,
+            ),
+            (
+                ChunkDigestType::default(),
+                vec![],
+                vec![0; MAX_CHUNK_DIGEST_SALT_SIZE + 1],
+                InvalidOptions::SaltTooLong,
+            ),
+        ];
+        for (chunk_digest, chunk_digest_key, chunk_digest_salt, err) in invalid {
+            let options = ChunkerOptions {
+                chunk_digest,
+                chunk_digest_key,
+                chunk_digest_salt,
+                ..ChunkerOptions::default()
+            };
+            assert_eq!(options.validate(), Err(err));
+        }
+
+        for (min_chunk, avg_chunk, max_chunk) in [(0, 0, 0), (2048, 1024, 4096), (0, 4096, 1024)] {
+            let options = ChunkerOptions {
+                min_chunk,
+                avg_chunk,
+                max_chunk,
+                ..ChunkerOptions::default()
+            };
+            assert_eq!(options.validate(), Err(InvalidOptions::ChunkSizes));
+        }
+    }
+
     #[test]
     fn it_doesnt_chunk_early_after_skipping() {
         let mut chunker = Chunker::new(ChunkerOptions::default());
@@ -242,4 +527,28 @@ mod tests {
 
         assert_eq!(chunker.count(), 1);
     }
+
+    #[test]
+    fn it_implements_write() {
+        let data = sample_data(256 * 1024);
+
+        let (expected_stats, expected) = reference_chunks(&data, ChunkerOptions::default());
+
+        // Chunk while writing the data elsewhere
+        let mut copy = Vec::new();
+        let mut chunker = Chunker::new(ChunkerOptions::default());
+        for piece in data.chunks(1000) {
+            copy.write_all(piece).unwrap();
+            chunker.write_all(piece).unwrap();
+        }
+        assert_eq!(copy, data);
+        let written = (chunker.finalize_reset(), chunker.collect::<Vec<Chunk>>());
+
+        let read = Chunker::from_reader(&data[..], ChunkerOptions::default()).unwrap();
+
+        for (stats, chunks) in [written, read] {
+            assert_eq!(stats.sha512, expected_stats.sha512);
+            assert_eq!(chunks, expected);
+        }
+    }
 }
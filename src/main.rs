@@ -2,122 +2,337 @@ use better_blockmap::*;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use clap::Parser;
-use flate2::write::{DeflateEncoder, GzEncoder};
-use flate2::Compression;
 use serde::Serialize;
 use std::default::Default;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
+use std::path::Path;
 
-#[derive(clap::ArgEnum, PartialEq, Debug, Clone)]
-enum CompressionType {
-    Gzip,
-    Deflate,
-}
-
-impl Default for CompressionType {
-    fn default() -> Self {
-        CompressionType::Gzip
-    }
+#[derive(clap::ArgEnum, PartialEq, Debug, Clone, Default)]
+enum OutputFormat {
+    #[default]
+    Blockmap,
+    Zsync,
+    Appx,
+    Caibx,
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Input binary file
-    #[clap(short, long)]
-    input: String,
+    #[clap(short, long, required = true)]
+    input: Option<String>,
 
-    /// Output blockmap file
+    /// Output file [default: append blockmap to input, <input>.zsync for zsync,
+    /// <input>.AppxBlockMap.xml for appx, <input>.caibx for caibx]
     #[clap(short, long)]
     output: Option<String>,
 
-    /// Compression
+    /// Output format
     #[clap(short, long, arg_enum, default_value_t)]
-    compression: CompressionType,
+    format: OutputFormat,
+
+    /// URL of the input written to the zsync file [default: input file name]
+    #[clap(long)]
+    url: Option<String>,
+
+    /// Blockmap encoding: json, cbor or msgpack. Only JSON is read by
+    /// electron-updater
+    #[clap(short, long, default_value = "json")]
+    encoding: BlockmapEncoding,
+
+    /// Blockmap version: 2, or 3 with chunk offsets and the file digest. Only
+    /// version 2 is read by electron-updater
+    #[clap(long, default_value = BLOCKMAP_VERSION, possible_values = [BLOCKMAP_VERSION, BLOCKMAP_V3_VERSION])]
+    blockmap_version: String,
+
+    /// Compression: gzip, deflate, zstd or brotli. Only gzip and deflate are
+    /// read by electron-updater
+    #[clap(short, long, default_value = "gzip")]
+    compression: BlockmapCompression,
+
+    /// Compression level [default: 9 for gzip and deflate, 19 for zstd, 11 for
+    /// brotli]
+    #[clap(long)]
+    compression_level: Option<u32>,
+
+    /// Chunking algorithm: rabin or fastcdc
+    #[clap(short, long, default_value = "rabin")]
+    algorithm: ChunkingAlgorithm,
+
+    /// Normalized chunking level [default: 0 for rabin, 2 for fastcdc]
+    #[clap(short, long)]
+    normalization: Option<u32>,
+
+    /// Print chunk size histogram
+    #[clap(long)]
+    histogram: bool,
+
+    /// Print statistics of chunks repeated within the file
+    #[clap(long)]
+    dedup: bool,
 
     /// Use zip file boundaries for splitting chunks
     #[clap(short = 'z', long)]
     detect_zip_boundary: bool,
+
+    /// Chunk digest: blake2b-<size in bytes>, sha256 or blake3
+    #[clap(long, default_value = "blake2b-18")]
+    chunk_digest: ChunkDigestType,
+
+    /// File with a secret key for Blake2b chunk digests. The whole file is the
+    /// key, including a trailing newline
+    #[clap(long)]
+    chunk_digest_key_file: Option<String>,
+
+    /// Public salt for Blake2b chunk digests, recorded in the blockmap
+    #[clap(long)]
+    chunk_digest_salt: Option<String>,
+
+    /// Additional whole-file digest to print: sha1, sha256 or blake3
+    #[clap(long = "file-digest")]
+    file_digests: Vec<FileDigestType>,
+
+    /// Number of worker threads, 0 to chunk on the main thread
+    #[clap(short = 'j', long, default_value_t = 0)]
+    threads: usize,
 }
 
-#[derive(Serialize)]
-struct BlockmapFile {
-    name: String,
-    offset: usize,
-    checksums: Vec<String>,
-    sizes: Vec<usize>,
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Convert a blockmap, zsync or caibx index to another of these formats
+    Convert(ConvertArgs),
+    /// Sign a blockmap with an Ed25519 key
+    Sign(SignArgs),
+    /// Check the Ed25519 signature of a blockmap
+    VerifySignature(VerifySignatureArgs),
+    /// Check a blockmap for structural problems
+    Lint(LintArgs),
+    /// Count the chunks of a blockmap that can be reused from an older one
+    Diff(DiffArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ConvertArgs {
+    /// Blockmap, zsync or caibx index
+    #[clap(short, long)]
+    input: String,
+
+    /// Output file
+    #[clap(short, long)]
+    output: String,
+
+    /// Output format: blockmap, zsync or caibx
+    #[clap(short, long)]
+    format: IndexFormat,
+
+    /// Original file, chunked at the boundaries of the index when its digests
+    /// can't be reused. Always needed for zsync
+    #[clap(long)]
+    file: Option<String>,
+
+    /// Chunk digest of the blockmap [default: digest of the index]
+    #[clap(long)]
+    chunk_digest: Option<ChunkDigestType>,
+
+    /// Blockmap encoding: json, cbor or msgpack
+    #[clap(short, long, default_value = "json")]
+    encoding: BlockmapEncoding,
+
+    /// Blockmap compression: gzip, deflate, zstd or brotli
+    #[clap(short, long, default_value = "gzip")]
+    compression: BlockmapCompression,
+
+    /// Compression level [default: 9 for gzip and deflate, 19 for zstd, 11 for
+    /// brotli]
+    #[clap(long)]
+    compression_level: Option<u32>,
+
+    /// URL of the original file written to the zsync file [default: file name]
+    #[clap(long)]
+    url: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SignArgs {
+    /// Blockmap file
+    #[clap(short, long)]
+    input: String,
+
+    /// File with the 32-byte Ed25519 secret key, raw or base64
+    #[clap(long)]
+    key_file: String,
+
+    /// Embed the signature in the blockmap instead of writing a detached one
+    #[clap(long)]
+    embed: bool,
+
+    /// Output file [default: <input>.sig, or the input itself with --embed]
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifySignatureArgs {
+    /// Blockmap file
+    #[clap(short, long)]
+    input: String,
+
+    /// File with the 32-byte Ed25519 public key, raw or base64
+    #[clap(long)]
+    public_key_file: String,
+
+    /// Detached signature [default: signature embedded in the blockmap]
+    #[clap(short, long)]
+    signature: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct LintArgs {
+    /// Blockmap file
+    #[clap(short, long)]
+    input: String,
+
+    /// Original file, to check that the chunks add up to its size
+    #[clap(long)]
+    file: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// Blockmap of the new version
+    #[clap(short, long)]
+    input: String,
+
+    /// Blockmap of the old version
+    #[clap(long)]
+    old: String,
 }
 
 #[derive(Serialize)]
 struct JSONStats {
     size: usize,
     sha512: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha1: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blake3: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    histogram: Option<SizeHistogram>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedup: Option<DedupStats>,
 }
 
 #[derive(Serialize)]
-struct Blockmap {
-    version: String,
-    files: Vec<BlockmapFile>,
+struct ZsyncStats {
+    size: u64,
+    sha1: String,
 }
 
-fn main() -> std::io::Result<()> {
-    let args = Args::parse();
+#[derive(Serialize)]
+struct ConvertStats {
+    chunks: usize,
+    rechunked: bool,
+}
 
-    let mut chunker = Chunker::new(ChunkerOptions {
-        detect_zip_boundary: args.detect_zip_boundary,
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignStats {
+    public_key: String,
+}
 
-        ..ChunkerOptions::default()
-    });
+#[derive(Serialize)]
+struct VerifySignatureStats {
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct LintStats {
+    issues: Vec<String>,
+}
 
-    let mut input = File::open(&args.input)?;
-    let mut buffer = [0; 16384];
+#[derive(Serialize)]
+struct AppxStats {
+    files: usize,
+    blocks: usize,
+}
 
-    loop {
-        let bytes_read = input.read(&mut buffer).expect("Failed to read bytes");
+fn invalid_input(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
 
-        chunker.update(&buffer[0..bytes_read]);
-        if bytes_read != buffer.len() {
-            break;
-        }
-    }
+fn write_zsync(input: &str, output: &str, url: Option<&String>) -> std::io::Result<()> {
+    let filename = Path::new(input)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| input.to_string());
+    let url = url.unwrap_or(&filename);
 
-    let stats = chunker.finalize_reset();
-    let chunks: Vec<Chunk> = chunker.collect();
-
-    let blockmap = Blockmap {
-        version: "2".to_string(),
-        files: vec![BlockmapFile {
-            name: "file".to_string(),
-            offset: 0,
-            checksums: chunks
-                .iter()
-                .map(|chunk| base64::encode(&chunk.digest))
-                .collect(),
-            sizes: chunks.iter().map(|chunk| chunk.size).collect(),
-        }],
-    };
+    let control = zsync_file(input, &filename, url)?;
+    std::fs::write(output, &control.data)?;
+
+    println!(
+        "{}",
+        serde_json::to_string(&ZsyncStats {
+            size: control.length,
+            sha1: base64::encode(&control.sha1),
+        })
+        .expect("JSON serialization")
+    );
 
-    let json = serde_json::to_string(&blockmap).expect("JSON serialization");
+    Ok(())
+}
 
-    let compressed = match args.compression {
-        CompressionType::Gzip => {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
-            encoder.write_all(json.as_bytes())?;
-            encoder.finish()?
-        }
-        CompressionType::Deflate => {
-            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
-            encoder.write_all(json.as_bytes())?;
-            encoder.finish()?
-        }
+fn write_appx(args: &Args, input: &str) -> std::io::Result<()> {
+    let files = appx_block_map_file(input)?;
+    let output = match &args.output {
+        Some(path) => path.clone(),
+        None => format!("{}.AppxBlockMap.xml", input),
     };
+    std::fs::write(output, appx_block_map_xml(&files))?;
+
+    println!(
+        "{}",
+        serde_json::to_string(&AppxStats {
+            files: files.len(),
+            blocks: files.iter().map(|file| file.blocks.len()).sum(),
+        })
+        .expect("JSON serialization")
+    );
+
+    Ok(())
+}
+
+fn compress_blockmap(
+    encoded: Result<Vec<u8>, BlockmapError>,
+    compression: BlockmapCompression,
+    compression_level: Option<u32>,
+) -> std::io::Result<Vec<u8>> {
+    let encoded = encoded.map_err(|err| invalid_input(&err.to_string()))?;
+
+    let level = compression_level.unwrap_or_else(|| compression.default_level());
+    compression.compress(&encoded, level)
+}
+
+fn write_blockmap(
+    args: &Args,
+    input: &str,
+    encoded: Result<Vec<u8>, BlockmapError>,
+) -> std::io::Result<()> {
+    let compressed = compress_blockmap(encoded, args.compression, args.compression_level)?;
 
     let mut output = match &args.output {
         // Create new file
         Some(path) => File::create(path)?,
         // Append to input
-        None => OpenOptions::new().append(true).open(&args.input)?,
+        None => OpenOptions::new().append(true).open(input)?,
     };
     output.write_all(&compressed)?;
     if args.output.is_none() {
@@ -126,11 +341,277 @@ fn main() -> std::io::Result<()> {
         output.write_all(&size)?;
     }
 
+    Ok(())
+}
+
+fn convert(args: &ConvertArgs) -> std::io::Result<()> {
+    let index = ChunkIndex::parse(&std::fs::read(&args.input)?)?;
+
+    let digest_type = match args.format {
+        IndexFormat::Zsync => {
+            let file = args
+                .file
+                .as_ref()
+                .ok_or_else(|| invalid_input("zsync output needs the original `--file`"))?;
+            return write_zsync(file, &args.output, args.url.as_ref());
+        }
+        IndexFormat::Caibx => ChunkDigestType::Sha256,
+        IndexFormat::Blockmap => match (args.chunk_digest, &index.digests) {
+            (Some(digest_type), _) => digest_type,
+            (None, Some((digest_type, _))) => *digest_type,
+            (None, None) => ChunkDigestType::default(),
+        },
+    };
+
+    let (chunks, rechunked) = match (index.chunks(digest_type), &args.file) {
+        (Some(chunks), _) => (chunks, false),
+        (None, Some(file)) => (index.chunk_file(file, digest_type)?, true),
+        (None, None) => {
+            return Err(invalid_input(&format!(
+                "{} digests of the {} index can't be reused, the original `--file` is needed",
+                digest_type, index.format
+            )))
+        }
+    };
+
+    let data = if args.format == IndexFormat::Caibx {
+        caibx_index(&index.caibx_options(), &chunks)
+    } else {
+        compress_blockmap(
+            index.blockmap(&chunks, digest_type).to_bytes(args.encoding),
+            args.compression,
+            args.compression_level,
+        )?
+    };
+    std::fs::write(&args.output, data)?;
+
+    println!(
+        "{}",
+        serde_json::to_string(&ConvertStats {
+            chunks: chunks.len(),
+            rechunked,
+        })
+        .expect("JSON serialization")
+    );
+
+    Ok(())
+}
+
+fn read_key(path: &str) -> std::io::Result<[u8; 32]> {
+    let data = std::fs::read(path)?;
+    let key = if data.len() == 32 {
+        data
+    } else {
+        base64::decode(String::from_utf8_lossy(&data).trim())
+            .map_err(|_| invalid_input("Key is neither raw nor base64"))?
+    };
+    key.try_into()
+        .map_err(|_| invalid_input("Key must be 32 bytes long"))
+}
+
+fn sign(args: &SignArgs) -> std::io::Result<()> {
+    let key = SigningKey::from_bytes(&read_key(&args.key_file)?);
+    let data = std::fs::read(&args.input)?;
+    let mut blockmap =
+        VersionedBlockmap::from_compressed(&data).map_err(|err| invalid_input(&err.to_string()))?;
+
+    if args.embed {
+        // Keep the encoding and compression of the input
+        let (compression, decompressed) = BlockmapCompression::decompress(&data)?;
+        let encoding = BlockmapEncoding::detect(&decompressed).unwrap_or_default();
+        blockmap.embed_signature(&key);
+
+        let compressed = compress_blockmap(blockmap.to_bytes(encoding), compression, None)?;
+        std::fs::write(args.output.as_ref().unwrap_or(&args.input), compressed)?;
+    } else {
+        let output = match &args.output {
+            Some(path) => path.clone(),
+            None => format!("{}.sig", args.input),
+        };
+        std::fs::write(output, blockmap.sign(&key))?;
+    }
+
+    println!(
+        "{}",
+        serde_json::to_string(&SignStats {
+            public_key: base64::encode(key.verifying_key().as_bytes()),
+        })
+        .expect("JSON serialization")
+    );
+
+    Ok(())
+}
+
+fn verify_signature(args: &VerifySignatureArgs) -> std::io::Result<()> {
+    let key = VerifyingKey::from_bytes(&read_key(&args.public_key_file)?)
+        .map_err(|_| invalid_input("Invalid public key"))?;
+    let signature = match &args.signature {
+        Some(path) => Some(std::fs::read(path)?),
+        None => None,
+    };
+
+    VersionedBlockmap::from_signed(&std::fs::read(&args.input)?, &key, signature.as_deref())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+
+    println!(
+        "{}",
+        serde_json::to_string(&VerifySignatureStats { valid: true }).expect("JSON serialization")
+    );
+
+    Ok(())
+}
+
+fn lint(args: &LintArgs) -> std::io::Result<()> {
+    let blockmap = read_blockmap(&args.input)?;
+
+    let result = match &args.file {
+        Some(path) => blockmap.validate_size(std::fs::metadata(path)?.len() as usize),
+        None => blockmap.validate(),
+    };
+    let issues: Vec<String> = result
+        .err()
+        .unwrap_or_default()
+        .iter()
+        .map(|issue| issue.to_string())
+        .collect();
+
+    println!(
+        "{}",
+        serde_json::to_string(&LintStats {
+            issues: issues.clone(),
+        })
+        .expect("JSON serialization")
+    );
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("blockmap has {} issues", issues.len()),
+        ))
+    }
+}
+
+fn read_blockmap(path: &str) -> std::io::Result<VersionedBlockmap> {
+    VersionedBlockmap::from_compressed(&std::fs::read(path)?)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))
+}
+
+fn diff(args: &DiffArgs) -> std::io::Result<()> {
+    let new = read_blockmap(&args.input)?.into_v2();
+    let old = read_blockmap(&args.old)?.into_v2();
+    let diff = new
+        .diff(&old)
+        .map_err(|err| invalid_input(&err.to_string()))?;
+
+    println!(
+        "{}",
+        serde_json::to_string(&diff).expect("JSON serialization")
+    );
+
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    match &args.command {
+        Some(Command::Convert(convert_args)) => return convert(convert_args),
+        Some(Command::Sign(sign_args)) => return sign(sign_args),
+        Some(Command::VerifySignature(verify_args)) => return verify_signature(verify_args),
+        Some(Command::Lint(lint_args)) => return lint(lint_args),
+        Some(Command::Diff(diff_args)) => return diff(diff_args),
+        None => {}
+    }
+    let input = args
+        .input
+        .as_deref()
+        .expect("required without a subcommand");
+    match args.format {
+        OutputFormat::Blockmap | OutputFormat::Caibx => {}
+        OutputFormat::Zsync => {
+            let output = match &args.output {
+                Some(path) => path.clone(),
+                None => format!("{}.zsync", input),
+            };
+            return write_zsync(input, &output, args.url.as_ref());
+        }
+        OutputFormat::Appx => return write_appx(&args, input),
+    }
+
+    let chunk_digest_key = match &args.chunk_digest_key_file {
+        Some(path) => std::fs::read(path)?,
+        None => Vec::new(),
+    };
+    let chunk_digest_salt = args
+        .chunk_digest_salt
+        .clone()
+        .unwrap_or_default()
+        .into_bytes();
+    if args.format == OutputFormat::Caibx && args.chunk_digest != ChunkDigestType::Sha256 {
+        return Err(invalid_input(
+            "casync index requires `--chunk-digest sha256`",
+        ));
+    }
+
+    let options = ChunkerOptions {
+        algorithm: args.algorithm,
+        normalization: args.normalization,
+        detect_zip_boundary: args.detect_zip_boundary,
+        chunk_digest: args.chunk_digest,
+        chunk_digest_key,
+        chunk_digest_salt,
+        file_digests: args.file_digests.clone(),
+
+        ..ChunkerOptions::default()
+    };
+    options
+        .validate()
+        .map_err(|err| invalid_input(&err.to_string()))?;
+    let mut histogram = SizeHistogram::new(&options);
+
+    let (stats, chunks) = if args.threads == 0 {
+        chunk_file(input, options.clone())?
+    } else {
+        chunk_file_parallel(input, options.clone(), args.threads)?
+    };
+    for chunk in &chunks {
+        histogram.add(chunk.size);
+    }
+
+    if args.format == OutputFormat::Caibx {
+        let output = match &args.output {
+            Some(path) => path.clone(),
+            None => format!("{}.caibx", input),
+        };
+        std::fs::write(output, caibx_index(&options, &chunks))?;
+    } else {
+        let blockmap = BlockmapV3::from_chunks(&options, &stats, &chunks);
+        if args.blockmap_version == BLOCKMAP_V3_VERSION {
+            write_blockmap(&args, input, blockmap.to_bytes(args.encoding))?;
+        } else {
+            write_blockmap(&args, input, blockmap.to_v2().to_bytes(args.encoding))?;
+        }
+    }
+
     println!(
         "{}",
         serde_json::to_string(&JSONStats {
             size: stats.size,
             sha512: base64::encode(&stats.sha512),
+            sha1: stats.digest(FileDigestType::Sha1).map(base64::encode),
+            sha256: stats.digest(FileDigestType::Sha256).map(base64::encode),
+            blake3: stats.digest(FileDigestType::Blake3).map(base64::encode),
+            histogram: if args.histogram {
+                Some(histogram)
+            } else {
+                None
+            },
+            dedup: if args.dedup {
+                Some(DedupStats::new(&chunks, &find_duplicates(&chunks)))
+            } else {
+                None
+            },
         })
         .expect("JSON serialization")
     );
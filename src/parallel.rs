@@ -0,0 +1,249 @@
+use sha2::digest::DynDigest;
+use sha2::{Digest, Sha512};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::{BoundaryFinder, Chunk, ChunkerOptions, RuntimeDigest, Stats};
+
+// Segments are at least this many maximum chunks long, so that the cost of
+// resynchronizing at the segment start stays small
+const MIN_SEGMENT_CHUNKS: usize = 64;
+
+// Each thread gets several segments to balance uneven chunking speed
+const SEGMENTS_PER_THREAD: usize = 4;
+
+/// Chunk boundaries found from the start of a segment as if a chunk ended
+/// there. Holds the cuts inside the segment plus the first one past its end
+/// (or the end of the data).
+struct Segment {
+    start: usize,
+    end: usize,
+    cuts: Vec<usize>,
+}
+
+impl Segment {
+    fn find(data: &[u8], options: &ChunkerOptions, start: usize, end: usize) -> Self {
+        let mut finder = BoundaryFinder::new(options);
+        let mut cuts = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            match finder.next_boundary(&data[offset..]) {
+                Some(size) => offset += size,
+                None => offset = data.len(),
+            }
+            cuts.push(offset);
+        }
+
+        Self { start, end, cuts }
+    }
+
+    // The state of the chunker is reset after every cut, so if the true
+    // chunking reaches one of the speculative cuts all the following ones are
+    // true as well
+    fn is_synchronized(&self, offset: usize) -> bool {
+        offset == self.start || self.cuts.binary_search(&offset).is_ok()
+    }
+}
+
+/// Finds chunk end offsets of `data` with up to `threads` threads. The result
+/// is the same as feeding `data` to `BoundaryFinder` in one go.
+///
+/// `data` is split into segments that are chunked in parallel as if each of
+/// them started right after a cut. The segments are then stitched in order:
+/// the true chunking is continued sequentially from the last true cut until
+/// it meets a speculative cut of the next segment.
+pub fn find_boundaries(data: &[u8], options: &ChunkerOptions, threads: usize) -> Vec<usize> {
+    let threads = threads.max(1);
+    let segment_size =
+        (data.len() / (threads * SEGMENTS_PER_THREAD)).max(options.max_chunk * MIN_SEGMENT_CHUNKS);
+    find_boundaries_with_segments(data, options, threads, segment_size)
+}
+
+fn find_boundaries_with_segments(
+    data: &[u8],
+    options: &ChunkerOptions,
+    threads: usize,
+    segment_size: usize,
+) -> Vec<usize> {
+    let segment_count = data.len().div_ceil(segment_size);
+    let next_segment = AtomicUsize::new(0);
+
+    let mut segments: Vec<(usize, Segment)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads.min(segment_count))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut segments = Vec::new();
+                    loop {
+                        let index = next_segment.fetch_add(1, Ordering::Relaxed);
+                        if index >= segment_count {
+                            break;
+                        }
+
+                        let start = index * segment_size;
+                        let end = (start + segment_size).min(data.len());
+                        segments.push((index, Segment::find(data, options, start, end)));
+                    }
+                    segments
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Boundary thread"))
+            .collect()
+    });
+    segments.sort_by_key(|(index, _)| *index);
+
+    let mut finder = BoundaryFinder::new(options);
+    let mut boundaries = Vec::new();
+    let mut offset = 0;
+    for (_, segment) in segments {
+        while offset < segment.end && !segment.is_synchronized(offset) {
+            match finder.next_boundary(&data[offset..]) {
+                Some(size) => offset += size,
+                None => offset = data.len(),
+            }
+            boundaries.push(offset);
+        }
+
+        if offset < segment.end {
+            let synchronized = segment.cuts.partition_point(|&cut| cut <= offset);
+            boundaries.extend_from_slice(&segment.cuts[synchronized..]);
+            offset = *boundaries.last().expect("Segment cuts");
+        }
+    }
+
+    boundaries
+}
+
+/// Chunks `data` with up to `threads` threads. Produces the same chunks and
+/// stats as `Chunker` with the same options.
+///
+/// Boundaries are found with `find_boundaries`, the chunk digests are
+/// computed in parallel afterwards, while the whole-file digests are computed
+/// on a separate thread the whole time.
+pub fn chunk_parallel(
+    data: &[u8],
+    options: &ChunkerOptions,
+    threads: usize,
+) -> (Stats, Vec<Chunk>) {
+    let threads = threads.max(1);
+
+    thread::scope(|scope| {
+        let file_digests = scope.spawn(|| {
+            let sha512 = Sha512::digest(data).to_vec();
+            let digests = options
+                .file_digests
+                .iter()
+                .map(|&digest_type| {
+                    let mut digest = RuntimeDigest::for_file(digest_type);
+                    digest.update(data);
+                    (digest_type, digest.finalize_reset().into_vec())
+                })
+                .collect();
+            (sha512, digests)
+        });
+
+        let boundaries = find_boundaries(data, options, threads);
+
+        let chunk_digest = RuntimeDigest::for_chunks(
+            options.chunk_digest,
+            &options.chunk_digest_key,
+            &options.chunk_digest_salt,
+        );
+        let group_size = boundaries.len().div_ceil(threads).max(1);
+        let chunks = thread::scope(|scope| {
+            let handles: Vec<_> = boundaries
+                .chunks(group_size)
+                .enumerate()
+                .map(|(i, ends)| {
+                    let mut start = if i == 0 {
+                        0
+                    } else {
+                        boundaries[i * group_size - 1]
+                    };
+                    let mut chunk_digest = chunk_digest.clone();
+                    scope.spawn(move || {
+                        ends.iter()
+                            .map(|&end| {
+                                chunk_digest.update(&data[start..end]);
+                                let chunk = Chunk {
+                                    size: end - start,
+                                    digest: chunk_digest.finalize_reset().into_vec(),
+                                };
+                                start = end;
+                                chunk
+                            })
+                            .collect::<Vec<Chunk>>()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("Chunk digest thread"))
+                .collect()
+        });
+        let (sha512, digests) = file_digests.join().expect("File digest thread");
+
+        (
+            Stats {
+                size: data.len(),
+                sha512,
+                digests,
+            },
+            chunks,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_data, reference_chunks};
+    use crate::{ChunkingAlgorithm, FileDigestType};
+
+    #[test]
+    fn it_matches_sequential_chunking() {
+        let mut data = random_data(0x9e3779b97f4a7c15, 2 * 1024 * 1024);
+        // Runs of zeroes end with `max_chunk` cuts that never synchronize
+        // with the speculative ones
+        data[100_000..300_000].fill(0);
+
+        for algorithm in [ChunkingAlgorithm::Rabin, ChunkingAlgorithm::FastCdc] {
+            let options = ChunkerOptions {
+                algorithm,
+                ..ChunkerOptions::default()
+            };
+
+            let mut finder = BoundaryFinder::new(&options);
+            finder.update(&data);
+            finder.finalize_reset();
+            let expected: Vec<usize> = finder.collect();
+
+            for segment_size in [1000, 12345, 100_000, data.len()] {
+                assert_eq!(
+                    find_boundaries_with_segments(&data, &options, 3, segment_size),
+                    expected
+                );
+            }
+        }
+
+        let options = ChunkerOptions {
+            file_digests: vec![FileDigestType::Sha256],
+            ..ChunkerOptions::default()
+        };
+        let (expected_stats, expected) = reference_chunks(&data, options.clone());
+
+        let (stats, chunks) = chunk_parallel(&data, &options, 3);
+        assert_eq!(stats.size, expected_stats.size);
+        assert_eq!(stats.sha512, expected_stats.sha512);
+        assert_eq!(stats.digests, expected_stats.digests);
+        assert_eq!(chunks, expected);
+
+        let (stats, chunks) = chunk_parallel(&[], &options, 3);
+        assert_eq!(stats.size, 0);
+        assert!(chunks.is_empty());
+    }
+}
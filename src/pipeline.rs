@@ -0,0 +1,294 @@
+use sha2::digest::DynDigest;
+use sha2::{Digest, Sha512};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::{BoundaryFinder, Chunk, ChunkerOptions, FileDigestType, RuntimeDigest, Stats};
+
+// Number of queued buffers/chunks per thread before `update` blocks
+const QUEUE_DEPTH: usize = 16;
+
+type Piece = (Arc<[u8]>, Range<usize>);
+type Job = (usize, Vec<Piece>);
+type FileDigests = (Vec<u8>, Vec<(FileDigestType, Vec<u8>)>);
+
+struct Workers {
+    buffers: SyncSender<Arc<[u8]>>,
+    file_digests: JoinHandle<FileDigests>,
+    jobs: SyncSender<Job>,
+    chunk_digests: Vec<JoinHandle<()>>,
+    results: Receiver<(usize, Chunk)>,
+}
+
+/// Chunker that finds boundaries on the calling thread while the chunk digests
+/// and the whole-file digests are computed on worker threads.
+///
+/// Produces the same chunks and stats as `Chunker` with the same options.
+pub struct PipelinedChunker {
+    options: ChunkerOptions,
+    threads: usize,
+    boundary_finder: BoundaryFinder,
+    workers: Option<Workers>,
+    pieces: Vec<Piece>,
+    chunk_size: usize,
+    total_size: usize,
+    // Chunks are numbered across `finalize_reset` so that the ones not yet
+    // returned by the iterator are kept in order
+    chunk_count: usize,
+    next_chunk: usize,
+    done: BTreeMap<usize, Chunk>,
+}
+
+impl PipelinedChunker {
+    /// `threads` is the number of threads computing chunk digests. One more
+    /// thread is used for the whole-file digests.
+    pub fn new(options: ChunkerOptions, threads: usize) -> Self {
+        Self {
+            boundary_finder: BoundaryFinder::new(&options),
+            options,
+            threads: threads.max(1),
+            workers: None,
+            pieces: Vec::new(),
+            chunk_size: 0,
+            total_size: 0,
+            chunk_count: 0,
+            next_chunk: 0,
+            done: BTreeMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let buffer: Arc<[u8]> = Arc::from(data);
+        self.workers()
+            .buffers
+            .send(buffer.clone())
+            .expect("File digest thread");
+        self.total_size += data.len();
+
+        let mut start = 0;
+        while let Some(end) = self.boundary_finder.next_boundary(&buffer[start..]) {
+            self.pieces.push((buffer.clone(), start..start + end));
+            self.send_chunk();
+            start += end;
+        }
+
+        if start < buffer.len() {
+            self.chunk_size += buffer.len() - start;
+            self.pieces.push((buffer, start..data.len()));
+        }
+    }
+
+    pub fn finalize_reset(&mut self) -> Stats {
+        // Make sure that the threads are running even for empty input
+        self.workers();
+
+        if self.chunk_size != 0 {
+            self.send_chunk();
+        }
+
+        let workers = self.workers.take().expect("Workers");
+        drop(workers.buffers);
+        drop(workers.jobs);
+
+        let (sha512, digests) = workers.file_digests.join().expect("File digest thread");
+        for handle in workers.chunk_digests {
+            handle.join().expect("Chunk digest thread");
+        }
+        self.done.extend(workers.results.try_iter());
+
+        let total_size = self.total_size;
+        self.total_size = 0;
+        self.chunk_size = 0;
+        self.boundary_finder.reset();
+
+        Stats {
+            size: total_size,
+            sha512,
+            digests,
+        }
+    }
+
+    fn send_chunk(&mut self) {
+        let pieces = std::mem::take(&mut self.pieces);
+        let index = self.chunk_count;
+        self.chunk_count += 1;
+        self.chunk_size = 0;
+
+        self.workers()
+            .jobs
+            .send((index, pieces))
+            .expect("Chunk digest thread");
+    }
+
+    fn workers(&mut self) -> &Workers {
+        if self.workers.is_none() {
+            self.workers = Some(self.spawn());
+        }
+        self.workers.as_ref().unwrap()
+    }
+
+    fn spawn(&self) -> Workers {
+        let (buffers, buffer_receiver) = sync_channel::<Arc<[u8]>>(QUEUE_DEPTH);
+        let file_digest_types = self.options.file_digests.clone();
+        let file_digests = thread::spawn(move || {
+            let mut sha512 = Sha512::new();
+            let mut digests: Vec<RuntimeDigest> = file_digest_types
+                .iter()
+                .map(|&digest_type| RuntimeDigest::for_file(digest_type))
+                .collect();
+
+            for buffer in buffer_receiver {
+                Digest::update(&mut sha512, &buffer);
+                for digest in digests.iter_mut() {
+                    digest.update(&buffer);
+                }
+            }
+
+            (
+                sha512.finalize().to_vec(),
+                file_digest_types
+                    .into_iter()
+                    .zip(digests.iter_mut())
+                    .map(|(digest_type, digest)| (digest_type, digest.finalize_reset().into_vec()))
+                    .collect(),
+            )
+        });
+
+        let (jobs, job_receiver) = sync_channel(QUEUE_DEPTH * self.threads);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, results) = channel();
+
+        let chunk_digest = RuntimeDigest::for_chunks(
+            self.options.chunk_digest,
+            &self.options.chunk_digest_key,
+            &self.options.chunk_digest_salt,
+        );
+        let chunk_digests = (0..self.threads)
+            .map(|_| {
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                let chunk_digest = chunk_digest.clone();
+                thread::spawn(move || {
+                    compute_chunk_digests(job_receiver, result_sender, chunk_digest)
+                })
+            })
+            .collect();
+
+        Workers {
+            buffers,
+            file_digests,
+            jobs,
+            chunk_digests,
+            results,
+        }
+    }
+}
+
+fn compute_chunk_digests(
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    results: Sender<(usize, Chunk)>,
+    mut chunk_digest: RuntimeDigest,
+) {
+    loop {
+        let job = jobs.lock().expect("Job queue").recv();
+        let (index, pieces) = match job {
+            Ok(job) => job,
+            Err(_) => break,
+        };
+
+        let mut size = 0;
+        for (buffer, range) in pieces {
+            size += range.len();
+            chunk_digest.update(&buffer[range]);
+        }
+
+        let chunk = Chunk {
+            size,
+            digest: chunk_digest.finalize_reset().into_vec(),
+        };
+        if results.send((index, chunk)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Returns every chunk whose end was found so far, same as `Chunker`. Waits
+/// for the worker threads if the next chunk is still being hashed.
+impl Iterator for PipelinedChunker {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_chunk == self.chunk_count {
+            return None;
+        }
+
+        // Results of all sent chunks are collected on `finalize_reset`, so
+        // the workers are still running if the chunk isn't done
+        while !self.done.contains_key(&self.next_chunk) {
+            let workers = self.workers.as_ref().expect("Workers");
+            let (index, chunk) = workers.results.recv().expect("Chunk digest thread");
+            self.done.insert(index, chunk);
+        }
+
+        let chunk = self.done.remove(&self.next_chunk)?;
+        self.next_chunk += 1;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_data, reference_chunks};
+
+    #[test]
+    fn it_matches_chunker() {
+        let options = ChunkerOptions {
+            file_digests: vec![FileDigestType::Sha1],
+            ..ChunkerOptions::default()
+        };
+
+        let data = random_data(0x2545f4914f6cdd1d, 1024 * 1024);
+
+        let (expected_stats, expected) = reference_chunks(&data, options.clone());
+
+        // Chunks are returned as soon as their end is found
+        let mut pipelined = PipelinedChunker::new(options.clone(), 3);
+        pipelined.update(&data[..100_000]);
+        let early: Vec<Chunk> = pipelined.by_ref().collect();
+        let mut end = 0;
+        let ended = expected
+            .iter()
+            .take_while(|chunk| {
+                end += chunk.size;
+                end <= 100_000
+            })
+            .count();
+        assert_eq!(early, expected[..ended]);
+
+        let mut pipelined = PipelinedChunker::new(options, 3);
+        for piece in data.chunks(10000) {
+            pipelined.update(piece);
+        }
+
+        // Can be reused after finalization
+        for _ in 0..2 {
+            let stats = pipelined.finalize_reset();
+            let chunks: Vec<Chunk> = pipelined.by_ref().collect();
+
+            assert_eq!(stats.size, expected_stats.size);
+            assert_eq!(stats.sha512, expected_stats.sha512);
+            assert_eq!(stats.digests, expected_stats.digests);
+            assert_eq!(chunks, expected);
+
+            pipelined.update(&data);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use sha2::digest::generic_array::GenericArray;
+use sha2::digest::{DynDigest, InvalidBufferSize};
+
+const BLOCK_SIZE: usize = 128;
+const OUTPUT_SIZE: usize = 64;
+
+const INITIAL_STATE: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+/// SHA-512 with a serializable state. Produces the same digests as
+/// `sha2::Sha512` using its block function.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "Sha512State")]
+pub struct ResumableSha512 {
+    state: [u64; 8],
+
+    // Input that doesn't fill a block yet
+    #[serde(with = "crate::encoding::bytes")]
+    buffer: Vec<u8>,
+    length: u64,
+}
+
+// Deserialized state before it is checked
+#[derive(Deserialize)]
+struct Sha512State {
+    state: [u64; 8],
+    #[serde(with = "crate::encoding::bytes")]
+    buffer: Vec<u8>,
+    length: u64,
+}
+
+impl TryFrom<Sha512State> for ResumableSha512 {
+    type Error = String;
+
+    fn try_from(state: Sha512State) -> Result<Self, Self::Error> {
+        if state.buffer.len() as u64 != state.length % BLOCK_SIZE as u64 {
+            return Err("SHA-512 buffer doesn't match the input length".to_string());
+        }
+
+        Ok(Self {
+            state: state.state,
+            buffer: state.buffer,
+            length: state.length,
+        })
+    }
+}
+
+impl Default for ResumableSha512 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResumableSha512 {
+    pub fn new() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            length: 0,
+        }
+    }
+
+    fn compress(&mut self, blocks: &[u8]) {
+        for block in blocks.chunks_exact(BLOCK_SIZE) {
+            sha2::compress512(
+                &mut self.state,
+                std::slice::from_ref(GenericArray::from_slice(block)),
+            );
+        }
+    }
+}
+
+impl DynDigest for ResumableSha512 {
+    fn update(&mut self, data: &[u8]) {
+        self.length += data.len() as u64;
+
+        let mut rest = data;
+        if !self.buffer.is_empty() {
+            let size = (BLOCK_SIZE - self.buffer.len()).min(rest.len());
+            self.buffer.extend_from_slice(&rest[..size]);
+            rest = &rest[size..];
+            if self.buffer.len() < BLOCK_SIZE {
+                return;
+            }
+
+            let block = std::mem::take(&mut self.buffer);
+            self.compress(&block);
+            self.buffer = block;
+            self.buffer.clear();
+        }
+
+        let full_blocks = rest.len() - rest.len() % BLOCK_SIZE;
+        self.compress(&rest[..full_blocks]);
+        self.buffer.extend_from_slice(&rest[full_blocks..]);
+    }
+
+    fn finalize_into(mut self, buf: &mut [u8]) -> Result<(), InvalidBufferSize> {
+        self.finalize_into_reset(buf)
+    }
+
+    fn finalize_into_reset(&mut self, out: &mut [u8]) -> Result<(), InvalidBufferSize> {
+        if out.len() != OUTPUT_SIZE {
+            return Err(InvalidBufferSize);
+        }
+
+        // 0x80, zeroes and the 128-bit big-endian length in bits
+        let bit_length = (self.length as u128) * 8;
+        let mut padding = vec![0x80];
+        let padded = (self.buffer.len() + 1 + 16).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        padding.resize(padded - self.buffer.len() - 16, 0);
+        padding.extend_from_slice(&bit_length.to_be_bytes());
+
+        let mut tail = std::mem::take(&mut self.buffer);
+        tail.extend_from_slice(&padding);
+        self.compress(&tail);
+
+        for (word, out) in self.state.iter().zip(out.chunks_exact_mut(8)) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+
+        self.reset();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn output_size(&self) -> usize {
+        OUTPUT_SIZE
+    }
+
+    fn box_clone(&self) -> Box<dyn DynDigest> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha512};
+
+    #[test]
+    fn it_matches_sha512() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+
+        // Cover all paddings and partially filled blocks
+        for size in 0..300 {
+            let mut hasher = ResumableSha512::new();
+            for piece in data[..size].chunks(size / 3 + 1) {
+                hasher.update(piece);
+            }
+
+            assert_eq!(
+                hasher.finalize_reset().to_vec(),
+                Sha512::digest(&data[..size]).to_vec(),
+                "size {}",
+                size
+            );
+        }
+
+        let mut hasher = ResumableSha512::new();
+        hasher.update(&data[..500]);
+        let mut restored: ResumableSha512 =
+            serde_json::from_slice(&serde_json::to_vec(&hasher).unwrap()).unwrap();
+        restored.update(&data[500..]);
+        assert_eq!(
+            restored.finalize_reset().to_vec(),
+            Sha512::digest(&data).to_vec()
+        );
+
+        // Buffer must hold the input after the last full block
+        let overfull = serde_json::json!({
+            "state": vec![0; 8],
+            "buffer": vec![0; 200],
+            "length": 200,
+        });
+        assert!(serde_json::from_value::<ResumableSha512>(overfull).is_err());
+    }
+}
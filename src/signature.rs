@@ -0,0 +1,125 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::{BlockmapEncoding, BlockmapError, VersionedBlockmap};
+
+impl VersionedBlockmap {
+    fn signature_field(&mut self) -> &mut Option<String> {
+        match self {
+            VersionedBlockmap::V2(blockmap) => &mut blockmap.signature,
+            VersionedBlockmap::V3(blockmap) => &mut blockmap.signature,
+        }
+    }
+
+    /// Bytes covered by the signature: the JSON encoding of the blockmap
+    /// without the embedded signature. They don't depend on the encoding and
+    /// compression the blockmap was stored with.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        *unsigned.signature_field() = None;
+        unsigned
+            .to_bytes(BlockmapEncoding::Json)
+            .expect("JSON serialization")
+    }
+
+    /// Returns a detached signature of `canonical_bytes`.
+    pub fn sign(&self, key: &SigningKey) -> Vec<u8> {
+        key.sign(&self.canonical_bytes()).to_bytes().to_vec()
+    }
+
+    /// Stores the signature in the blockmap itself.
+    pub fn embed_signature(&mut self, key: &SigningKey) {
+        let signature = base64::encode(self.sign(key));
+        *self.signature_field() = Some(signature);
+    }
+
+    /// Checks the `detached` signature, or the embedded one if there is no
+    /// detached signature.
+    pub fn verify(&self, key: &VerifyingKey, detached: Option<&[u8]>) -> Result<(), BlockmapError> {
+        let embedded = match self {
+            VersionedBlockmap::V2(blockmap) => &blockmap.signature,
+            VersionedBlockmap::V3(blockmap) => &blockmap.signature,
+        };
+        let bytes = match (detached, embedded) {
+            (Some(detached), _) => detached.to_vec(),
+            (None, Some(embedded)) => {
+                base64::decode(embedded).map_err(|_| BlockmapError::InvalidSignature)?
+            }
+            (None, None) => return Err(BlockmapError::MissingSignature),
+        };
+
+        let signature =
+            Signature::from_slice(&bytes).map_err(|_| BlockmapError::InvalidSignature)?;
+        key.verify_strict(&self.canonical_bytes(), &signature)
+            .map_err(|_| BlockmapError::InvalidSignature)
+    }
+
+    /// Same as `from_compressed`, but fails unless the blockmap is signed by
+    /// `key`. Use it whenever the blockmap comes from an untrusted location.
+    pub fn from_signed(
+        data: &[u8],
+        key: &VerifyingKey,
+        detached: Option<&[u8]>,
+    ) -> Result<Self, BlockmapError> {
+        let blockmap = Self::from_compressed(data)?;
+        blockmap.verify(key, detached)?;
+        Ok(blockmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Blockmap, BlockmapCompression, BlockmapFile, ChunkerOptions};
+
+    fn blockmap() -> VersionedBlockmap {
+        VersionedBlockmap::V2(Blockmap::new(
+            (&ChunkerOptions::default()).into(),
+            vec![BlockmapFile {
+                name: "file".to_string(),
+                offset: 0,
+                checksums: vec!["AAECAwQFBgcICQoLDA0ODxAR".to_string()],
+                sizes: vec![12345],
+            }],
+        ))
+    }
+
+    #[test]
+    fn it_verifies_signatures() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other_key = SigningKey::from_bytes(&[8; 32]);
+
+        let blockmap = blockmap();
+        let detached = blockmap.sign(&key);
+        assert_eq!(
+            blockmap.verify(&key.verifying_key(), Some(&detached)).ok(),
+            Some(())
+        );
+        assert!(matches!(
+            blockmap.verify(&other_key.verifying_key(), Some(&detached)),
+            Err(BlockmapError::InvalidSignature)
+        ));
+        assert!(matches!(
+            blockmap.verify(&key.verifying_key(), None),
+            Err(BlockmapError::MissingSignature)
+        ));
+
+        // Embedded signatures survive re-encoding and compression
+        let mut signed = blockmap;
+        signed.embed_signature(&key);
+        let data = BlockmapCompression::Zstd
+            .compress(&signed.to_bytes(BlockmapEncoding::Cbor).unwrap(), 3)
+            .unwrap();
+        assert!(VersionedBlockmap::from_signed(&data, &key.verifying_key(), None).is_ok());
+
+        // Any change of the blockmap invalidates the signature
+        if let VersionedBlockmap::V2(blockmap) = &mut signed {
+            blockmap.files[0].sizes[0] += 1;
+        }
+        let data = signed.to_bytes(BlockmapEncoding::Json).unwrap();
+        let data = BlockmapCompression::Gzip.compress(&data, 9).unwrap();
+        assert!(matches!(
+            VersionedBlockmap::from_signed(&data, &key.verifying_key(), None),
+            Err(BlockmapError::InvalidSignature)
+        ));
+    }
+}
@@ -1,4 +1,5 @@
 pub const DEFAULT_WINDOW_SIZE: usize = 64;
+pub const POLYNOMIAL: u64 = 0xbfe6b8a5bf378d83;
 
 #[derive(Debug)]
 pub struct Table {
@@ -0,0 +1,64 @@
+// Input data shared by the tests
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Chunk, Chunker, ChunkerOptions, Stats};
+
+/// Deterministic data without repeated chunks.
+pub(crate) fn sample_data(size: u32) -> Vec<u8> {
+    (0..size)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect()
+}
+
+/// Xorshift64 generator, for data and offsets that look random.
+pub(crate) fn xorshift(mut state: u64) -> impl FnMut() -> u64 {
+    move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }
+}
+
+pub(crate) fn random_data(seed: u64, size: usize) -> Vec<u8> {
+    let mut next = xorshift(seed);
+    (0..size).map(|_| (next() >> 32) as u8).collect()
+}
+
+/// Stats and chunks of `data` from a plain `Chunker`, for comparing other
+/// ways of chunking with.
+pub(crate) fn reference_chunks(data: &[u8], options: ChunkerOptions) -> (Stats, Vec<Chunk>) {
+    let mut chunker = Chunker::new(options);
+    chunker.update(data);
+    (chunker.finalize_reset(), chunker.collect())
+}
+
+/// File in the temporary directory with a name unique to the test run,
+/// removed on drop even if the test fails.
+pub(crate) struct TempFile(PathBuf);
+
+impl TempFile {
+    pub(crate) fn new(data: &[u8]) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "better-blockmap-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, data).unwrap();
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
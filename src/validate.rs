@@ -0,0 +1,458 @@
+use std::fmt;
+
+use crate::{Blockmap, BlockmapV3, ChunkDigestType, ChunkerParameters, VersionedBlockmap};
+
+/// Structural problem of a blockmap found by `validate`. `file` and `chunk`
+/// are indices into `files` and into the chunks of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockmapIssue {
+    /// `checksums` and `sizes` have different lengths
+    LengthMismatch {
+        file: usize,
+        checksums: usize,
+        sizes: usize,
+    },
+    /// `parameters.digest` is not a known chunk digest
+    UnknownDigest(String),
+    /// Checksum is not base64 or has the wrong length for the digest
+    InvalidChecksum { file: usize, chunk: usize },
+    /// Chunk is empty, or smaller than `minChunk` without being the last one
+    ChunkTooSmall {
+        file: usize,
+        chunk: usize,
+        size: usize,
+    },
+    ChunkTooLarge {
+        file: usize,
+        chunk: usize,
+        size: usize,
+    },
+    /// File starts before the end of the previous file
+    OverlappingFile { file: usize },
+    /// Offset and chunk sizes of the file add up to more than `usize::MAX`
+    FileOutOfRange { file: usize },
+    /// Blockmap covers `actual` bytes instead of the size of the input
+    SizeMismatch { expected: usize, actual: usize },
+    /// v3 `size` field is `declared` instead of the size of the input
+    DeclaredSizeMismatch { declared: usize, actual: usize },
+    /// v3 `offsets` don't match the sizes
+    OffsetMismatch { file: usize, chunk: usize },
+    /// v3 `dedup` entry doesn't point to an earlier chunk with the same
+    /// checksum and size
+    InvalidDuplicate { file: usize, chunk: usize },
+}
+
+impl fmt::Display for BlockmapIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockmapIssue::LengthMismatch {
+                file,
+                checksums,
+                sizes,
+            } => write!(
+                f,
+                "file {} has {} checksums and {} sizes",
+                file, checksums, sizes
+            ),
+            BlockmapIssue::UnknownDigest(digest) => write!(f, "unknown digest `{}`", digest),
+            BlockmapIssue::InvalidChecksum { file, chunk } => {
+                write!(f, "file {} chunk {} has an invalid checksum", file, chunk)
+            }
+            BlockmapIssue::ChunkTooSmall { file, chunk, size } => {
+                write!(f, "file {} chunk {} is too small: {}", file, chunk, size)
+            }
+            BlockmapIssue::ChunkTooLarge { file, chunk, size } => {
+                write!(f, "file {} chunk {} is too large: {}", file, chunk, size)
+            }
+            BlockmapIssue::OverlappingFile { file } => {
+                write!(
+                    f,
+                    "file {} starts before the end of the previous file",
+                    file
+                )
+            }
+            BlockmapIssue::FileOutOfRange { file } => {
+                write!(f, "file {} ends beyond the largest possible offset", file)
+            }
+            BlockmapIssue::SizeMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "blockmap covers {} bytes instead of {}",
+                    actual, expected
+                )
+            }
+            BlockmapIssue::DeclaredSizeMismatch { declared, actual } => {
+                write!(
+                    f,
+                    "blockmap declares size {} instead of {}",
+                    declared, actual
+                )
+            }
+            BlockmapIssue::OffsetMismatch { file, chunk } => {
+                write!(f, "file {} chunk {} has a wrong offset", file, chunk)
+            }
+            BlockmapIssue::InvalidDuplicate { file, chunk } => {
+                write!(f, "file {} chunk {} is not a duplicate", file, chunk)
+            }
+        }
+    }
+}
+
+impl Blockmap {
+    /// Checks the structure of the blockmap. Chunk sizes and checksum lengths
+    /// are only checked against `parameters` when they are present.
+    pub fn validate(&self) -> Result<(), Vec<BlockmapIssue>> {
+        into_result(self.issues(None))
+    }
+
+    /// Same as `validate`, and also checks that the files end at `size`, the
+    /// size of the input.
+    pub fn validate_size(&self, size: usize) -> Result<(), Vec<BlockmapIssue>> {
+        into_result(self.issues(Some(size)))
+    }
+
+    fn issues(&self, size: Option<usize>) -> Vec<BlockmapIssue> {
+        let files: Vec<FileChunks> = self
+            .files
+            .iter()
+            .map(|file| FileChunks {
+                offset: file.offset,
+                checksums: &file.checksums,
+                sizes: &file.sizes,
+            })
+            .collect();
+        check_files(self.parameters.as_ref(), &files, size)
+    }
+}
+
+impl BlockmapV3 {
+    /// Same checks as `Blockmap::validate`, plus the input size, chunk
+    /// offsets and the dedup table.
+    pub fn validate(&self) -> Result<(), Vec<BlockmapIssue>> {
+        into_result(self.issues(self.size))
+    }
+
+    /// Same as `validate`, with `size` from the input instead of the
+    /// blockmap. The `size` field must match it as well.
+    pub fn validate_size(&self, size: usize) -> Result<(), Vec<BlockmapIssue>> {
+        let mut issues = self.issues(size);
+        if self.size != size {
+            issues.push(BlockmapIssue::DeclaredSizeMismatch {
+                declared: self.size,
+                actual: size,
+            });
+        }
+        into_result(issues)
+    }
+
+    fn issues(&self, size: usize) -> Vec<BlockmapIssue> {
+        let files: Vec<FileChunks> = self
+            .files
+            .iter()
+            .map(|file| FileChunks {
+                offset: file.offset,
+                checksums: &file.checksums,
+                sizes: &file.sizes,
+            })
+            .collect();
+        let mut issues = check_files(Some(&self.parameters), &files, Some(size));
+
+        for (i, file) in self.files.iter().enumerate() {
+            // Overflowing offsets are reported by `check_files`, no offset
+            // after them can be right
+            let mut offset = Some(file.offset);
+            for (chunk, &size) in file.sizes.iter().enumerate() {
+                if offset.is_none() || file.offsets.get(chunk) != offset.as_ref() {
+                    issues.push(BlockmapIssue::OffsetMismatch { file: i, chunk });
+                    break;
+                }
+                offset = offset.and_then(|offset| offset.checked_add(size));
+            }
+            if file.offsets.len() > file.sizes.len() {
+                issues.push(BlockmapIssue::OffsetMismatch {
+                    file: i,
+                    chunk: file.sizes.len(),
+                });
+            }
+
+            for &(chunk, first) in &file.dedup {
+                let is_duplicate = first < chunk
+                    && chunk < file.sizes.len().min(file.checksums.len())
+                    && file.sizes[chunk] == file.sizes[first]
+                    && file.checksums[chunk] == file.checksums[first];
+                if !is_duplicate {
+                    issues.push(BlockmapIssue::InvalidDuplicate { file: i, chunk });
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+impl VersionedBlockmap {
+    pub fn validate(&self) -> Result<(), Vec<BlockmapIssue>> {
+        match self {
+            VersionedBlockmap::V2(blockmap) => blockmap.validate(),
+            VersionedBlockmap::V3(blockmap) => blockmap.validate(),
+        }
+    }
+
+    pub fn validate_size(&self, size: usize) -> Result<(), Vec<BlockmapIssue>> {
+        match self {
+            VersionedBlockmap::V2(blockmap) => blockmap.validate_size(size),
+            VersionedBlockmap::V3(blockmap) => blockmap.validate_size(size),
+        }
+    }
+}
+
+struct FileChunks<'a> {
+    offset: usize,
+    checksums: &'a [String],
+    sizes: &'a [usize],
+}
+
+fn into_result(issues: Vec<BlockmapIssue>) -> Result<(), Vec<BlockmapIssue>> {
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(issues)
+    }
+}
+
+fn check_files(
+    parameters: Option<&ChunkerParameters>,
+    files: &[FileChunks],
+    size: Option<usize>,
+) -> Vec<BlockmapIssue> {
+    let mut issues = Vec::new();
+
+    // Without parameters, checksums only have to be of the same length
+    let mut digest_size = None;
+    if let Some(parameters) = parameters {
+        match parameters.digest.parse::<ChunkDigestType>() {
+            Ok(digest_type) => digest_size = Some(digest_type.output_size()),
+            Err(_) => issues.push(BlockmapIssue::UnknownDigest(parameters.digest.clone())),
+        }
+    }
+
+    // `None` once a file ends beyond `usize::MAX`
+    let mut end = Some(0);
+    for (i, file) in files.iter().enumerate() {
+        if file.checksums.len() != file.sizes.len() {
+            issues.push(BlockmapIssue::LengthMismatch {
+                file: i,
+                checksums: file.checksums.len(),
+                sizes: file.sizes.len(),
+            });
+        }
+
+        for (chunk, checksum) in file.checksums.iter().enumerate() {
+            let is_valid = match base64::decode(checksum) {
+                Ok(digest) => *digest_size.get_or_insert(digest.len()) == digest.len(),
+                Err(_) => false,
+            };
+            if !is_valid {
+                issues.push(BlockmapIssue::InvalidChecksum { file: i, chunk });
+            }
+        }
+
+        for (chunk, &size) in file.sizes.iter().enumerate() {
+            let is_last = chunk + 1 == file.sizes.len();
+            let (min_chunk, max_chunk) = match parameters {
+                // Zip headers cut chunks regardless of `minChunk`
+                Some(parameters) if !parameters.detect_zip_boundary && !is_last => {
+                    (parameters.min_chunk.max(1), parameters.max_chunk)
+                }
+                Some(parameters) => (1, parameters.max_chunk),
+                None => (1, usize::MAX),
+            };
+
+            if size < min_chunk {
+                issues.push(BlockmapIssue::ChunkTooSmall {
+                    file: i,
+                    chunk,
+                    size,
+                });
+            } else if size > max_chunk {
+                issues.push(BlockmapIssue::ChunkTooLarge {
+                    file: i,
+                    chunk,
+                    size,
+                });
+            }
+        }
+
+        if i > 0 && end.is_none_or(|end| file.offset < end) {
+            issues.push(BlockmapIssue::OverlappingFile { file: i });
+        }
+        end = file
+            .sizes
+            .iter()
+            .try_fold(file.offset, |end, &size| end.checked_add(size));
+        if end.is_none() {
+            issues.push(BlockmapIssue::FileOutOfRange { file: i });
+        }
+    }
+
+    if let (Some(expected), Some(end)) = (size, end) {
+        if end != expected {
+            issues.push(BlockmapIssue::SizeMismatch {
+                expected,
+                actual: end,
+            });
+        }
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{reference_chunks, sample_data};
+    use crate::{BlockmapFile, ChunkerOptions};
+
+    #[test]
+    fn it_accepts_generated_blockmaps() {
+        let data = sample_data(256 * 1024);
+
+        for detect_zip_boundary in [false, true] {
+            let options = ChunkerOptions {
+                detect_zip_boundary,
+                ..ChunkerOptions::default()
+            };
+            let (stats, chunks) = reference_chunks(&data, options.clone());
+
+            let blockmap = BlockmapV3::from_chunks(&options, &stats, &chunks);
+            assert_eq!(blockmap.validate(), Ok(()));
+            assert_eq!(blockmap.to_v2().validate_size(data.len()), Ok(()));
+
+            let mut broken = blockmap.clone();
+            broken.files[0].offsets[1] += 1;
+            broken.files[0].dedup.push((1, 0));
+            assert_eq!(
+                broken.validate_size(data.len() + 1),
+                Err(vec![
+                    BlockmapIssue::SizeMismatch {
+                        expected: data.len() + 1,
+                        actual: data.len()
+                    },
+                    BlockmapIssue::OffsetMismatch { file: 0, chunk: 1 },
+                    BlockmapIssue::InvalidDuplicate { file: 0, chunk: 1 },
+                    BlockmapIssue::DeclaredSizeMismatch {
+                        declared: data.len(),
+                        actual: data.len() + 1
+                    },
+                ])
+            );
+
+            // Chunks add up to the input, only the `size` field is wrong
+            let mut wrong_size = blockmap.clone();
+            wrong_size.size += 1;
+            assert_eq!(
+                wrong_size.validate_size(data.len()),
+                Err(vec![BlockmapIssue::DeclaredSizeMismatch {
+                    declared: data.len() + 1,
+                    actual: data.len()
+                }])
+            );
+        }
+    }
+
+    #[test]
+    fn it_reports_issues() {
+        let file = |offset: usize, checksums: Vec<&str>, sizes: Vec<usize>| BlockmapFile {
+            name: "file".to_string(),
+            offset,
+            checksums: checksums.into_iter().map(String::from).collect(),
+            sizes,
+        };
+        let checksum = "AAECAwQFBgcICQoLDA0ODxAR";
+
+        let blockmap = Blockmap::new(
+            (&ChunkerOptions::default()).into(),
+            vec![
+                file(0, vec![checksum, "AAEC", "not base64!"], vec![8192, 100]),
+                file(8000, vec![checksum, checksum], vec![40000, 9000]),
+            ],
+        );
+        assert_eq!(
+            blockmap.validate_size(60000),
+            Err(vec![
+                BlockmapIssue::LengthMismatch {
+                    file: 0,
+                    checksums: 3,
+                    sizes: 2
+                },
+                BlockmapIssue::InvalidChecksum { file: 0, chunk: 1 },
+                BlockmapIssue::InvalidChecksum { file: 0, chunk: 2 },
+                BlockmapIssue::ChunkTooLarge {
+                    file: 1,
+                    chunk: 0,
+                    size: 40000
+                },
+                BlockmapIssue::OverlappingFile { file: 1 },
+                BlockmapIssue::SizeMismatch {
+                    expected: 60000,
+                    actual: 57000
+                },
+            ])
+        );
+
+        // Checksums of legacy blockmaps only have to agree on the length
+        let legacy = Blockmap {
+            parameters: None,
+            ..Blockmap::new(
+                (&ChunkerOptions::default()).into(),
+                vec![file(0, vec!["AAEC", checksum, "AAEC"], vec![1, 0, 1])],
+            )
+        };
+        assert_eq!(
+            legacy.validate(),
+            Err(vec![
+                BlockmapIssue::InvalidChecksum { file: 0, chunk: 1 },
+                BlockmapIssue::ChunkTooSmall {
+                    file: 0,
+                    chunk: 1,
+                    size: 0
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn it_reports_files_out_of_range() {
+        let blockmap = Blockmap::new(
+            (&ChunkerOptions::default()).into(),
+            vec![BlockmapFile {
+                name: "file".to_string(),
+                offset: usize::MAX,
+                checksums: vec!["AAECAwQFBgcICQoLDA0ODxAR".to_string()],
+                sizes: vec![1],
+            }],
+        );
+        assert_eq!(
+            blockmap.validate_size(0),
+            Err(vec![BlockmapIssue::FileOutOfRange { file: 0 }])
+        );
+
+        let data = sample_data(256 * 1024);
+        let options = ChunkerOptions::default();
+        let (stats, chunks) = reference_chunks(&data, options.clone());
+        let mut blockmap = BlockmapV3::from_chunks(&options, &stats, &chunks);
+        blockmap.files[0].sizes[1] = usize::MAX;
+        assert_eq!(
+            blockmap.validate(),
+            Err(vec![
+                BlockmapIssue::ChunkTooLarge {
+                    file: 0,
+                    chunk: 1,
+                    size: usize::MAX
+                },
+                BlockmapIssue::FileOutOfRange { file: 0 },
+                BlockmapIssue::OffsetMismatch { file: 0, chunk: 2 },
+            ])
+        );
+    }
+}
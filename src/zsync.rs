@@ -0,0 +1,261 @@
+use md4::{Digest, Md4};
+use sha2::digest::DynDigest;
+use std::io;
+use std::path::Path;
+
+use crate::file::for_each_buffer;
+use crate::{FileDigestType, RuntimeDigest};
+
+/// Version of zsyncmake whose output we reproduce.
+pub const ZSYNC_VERSION: &str = "0.6.2";
+
+const RSUM_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 16;
+
+/// Block size zsyncmake picks for a file of `length` bytes.
+pub fn default_zsync_block_size(length: u64) -> usize {
+    if length < 100_000_000 {
+        2048
+    } else {
+        4096
+    }
+}
+
+/// Builds a `.zsync` control file: the header followed by the rsum and MD4
+/// checksums of every block. The last block is padded with zeroes.
+pub struct ZsyncBuilder {
+    block_size: usize,
+    block: Vec<u8>,
+
+    // Full rsum and MD4 of every block, truncated to `Hash-Lengths` once the
+    // file length is known
+    sums: Vec<([u8; RSUM_SIZE], [u8; CHECKSUM_SIZE])>,
+    sha1: RuntimeDigest,
+    length: u64,
+}
+
+/// Result of `ZsyncBuilder::finalize`.
+pub struct ZsyncControl {
+    pub length: u64,
+    pub sha1: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl ZsyncBuilder {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            block: Vec::with_capacity(block_size),
+            sums: Vec::new(),
+            sha1: RuntimeDigest::for_file(FileDigestType::Sha1),
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha1.update(data);
+        self.length += data.len() as u64;
+
+        let mut rest = data;
+        if !self.block.is_empty() {
+            let size = (self.block_size - self.block.len()).min(rest.len());
+            self.block.extend_from_slice(&rest[..size]);
+            rest = &rest[size..];
+            if self.block.len() < self.block_size {
+                return;
+            }
+
+            let sum = block_sum(&self.block);
+            self.sums.push(sum);
+            self.block.clear();
+        }
+
+        let mut blocks = rest.chunks_exact(self.block_size);
+        for block in &mut blocks {
+            self.sums.push(block_sum(block));
+        }
+        self.block.extend_from_slice(blocks.remainder());
+    }
+
+    /// `filename` and `url` are written to the header. The URL is usually
+    /// relative to the control file.
+    pub fn finalize(mut self, filename: &str, url: &str) -> ZsyncControl {
+        if !self.block.is_empty() {
+            self.block.resize(self.block_size, 0);
+            let sum = block_sum(&self.block);
+            self.sums.push(sum);
+        }
+
+        let (seq_matches, rsum_len, checksum_len) = hash_lengths(self.length, self.block_size);
+        let sha1 = self.sha1.finalize_reset().into_vec();
+
+        let mut data = format!(
+            concat!(
+                "zsync: {}\n",
+                "Filename: {}\n",
+                "Blocksize: {}\n",
+                "Length: {}\n",
+                "Hash-Lengths: {},{},{}\n",
+                "URL: {}\n",
+                "SHA-1: {}\n",
+                "\n"
+            ),
+            ZSYNC_VERSION,
+            filename,
+            self.block_size,
+            self.length,
+            seq_matches,
+            rsum_len,
+            checksum_len,
+            url,
+            to_hex(&sha1),
+        )
+        .into_bytes();
+
+        for (rsum, checksum) in &self.sums {
+            data.extend_from_slice(&rsum[RSUM_SIZE - rsum_len..]);
+            data.extend_from_slice(&checksum[..checksum_len]);
+        }
+
+        ZsyncControl {
+            length: self.length,
+            sha1,
+            data,
+        }
+    }
+}
+
+/// Builds the `.zsync` control file for the file at `path`.
+pub fn zsync_file<P: AsRef<Path>>(path: P, filename: &str, url: &str) -> io::Result<ZsyncControl> {
+    let length = std::fs::metadata(&path)?.len();
+    let mut builder = ZsyncBuilder::new(default_zsync_block_size(length));
+    for_each_buffer(path, |data| builder.update(data))?;
+    Ok(builder.finalize(filename, url))
+}
+
+/// Reads `Blocksize` and `Length` from the header of a `.zsync` file.
+pub(crate) fn parse_zsync_header(data: &[u8]) -> io::Result<(usize, u64)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if !data.starts_with(b"zsync: ") {
+        return Err(invalid("not a zsync file"));
+    }
+
+    let mut block_size = None;
+    let mut length = None;
+    for line in data.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            break;
+        }
+        let line = String::from_utf8_lossy(line);
+        if let Some(value) = line.strip_prefix("Blocksize: ") {
+            block_size = value.parse().ok();
+        } else if let Some(value) = line.strip_prefix("Length: ") {
+            length = value.parse().ok();
+        }
+    }
+
+    match (block_size, length) {
+        (Some(block_size), Some(length)) if block_size > 0 => Ok((block_size, length)),
+        _ => Err(invalid("zsync header has no valid block size and length")),
+    }
+}
+
+// Rolling checksum of zsync: two 16-bit sums, stored big-endian
+fn block_sum(block: &[u8]) -> ([u8; RSUM_SIZE], [u8; CHECKSUM_SIZE]) {
+    let mut a = 0u16;
+    let mut b = 0u16;
+    for (i, &c) in block.iter().enumerate() {
+        a = a.wrapping_add(c as u16);
+        b = b.wrapping_add(((block.len() - i) as u16).wrapping_mul(c as u16));
+    }
+
+    let mut rsum = [0; RSUM_SIZE];
+    rsum[..2].copy_from_slice(&a.to_be_bytes());
+    rsum[2..].copy_from_slice(&b.to_be_bytes());
+
+    (rsum, Md4::digest(block).into())
+}
+
+// Same formulas as zsyncmake, which trades checksum size for the chance of
+// false block matches
+fn hash_lengths(length: u64, block_size: usize) -> (usize, usize, usize) {
+    let len = length as f64;
+    let blocks = (length / block_size as u64) as f64;
+    let seq_matches = if length > block_size as u64 { 2 } else { 1 };
+
+    let rsum_len =
+        (((len.ln() + (block_size as f64).ln()) / 2f64.ln() - 8.6) / seq_matches as f64 / 8.0)
+            .ceil();
+    let rsum_len = (rsum_len.max(2.0) as usize).min(RSUM_SIZE);
+
+    let checksum_len =
+        ((20.0 + (len.ln() + (1.0 + blocks).ln()) / 2f64.ln()) / seq_matches as f64 / 8.0).ceil();
+    let checksum_len2 = ((7.9 + (20.0 + (1.0 + blocks).ln() / 2f64.ln())) / 8.0) as usize;
+    let checksum_len = (checksum_len.max(0.0) as usize)
+        .max(checksum_len2)
+        .min(CHECKSUM_SIZE);
+
+    (seq_matches, rsum_len, checksum_len)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_hash_lengths() {
+        assert_eq!(hash_lengths(1_000_000, 2048), (2, 2, 4));
+        assert_eq!(hash_lengths(1_000, 2048), (1, 2, 4));
+        assert_eq!(hash_lengths(0, 2048), (1, 2, 3));
+        assert_eq!(hash_lengths(2_000_000_000, 4096), (2, 3, 5));
+    }
+
+    #[test]
+    fn it_builds_control_file() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut builder = ZsyncBuilder::new(2048);
+        for piece in data.chunks(1000) {
+            builder.update(piece);
+        }
+        let control = builder.finalize("app.AppImage", "app.AppImage");
+
+        let header = concat!(
+            "zsync: 0.6.2\n",
+            "Filename: app.AppImage\n",
+            "Blocksize: 2048\n",
+            "Length: 5000\n",
+            "Hash-Lengths: 2,2,3\n",
+            "URL: app.AppImage\n",
+            "SHA-1: e6937aa016bc06eda81d3b227fffde825d881ce5\n",
+            "\n"
+        );
+        assert_eq!(&control.data[..header.len()], header.as_bytes());
+        assert_eq!(control.length, 5000);
+
+        // Three blocks with 2-byte rsums and 3-byte MD4s
+        let sums = &control.data[header.len()..];
+        assert_eq!(sums.len(), 3 * 5);
+
+        let mut last_block = data[4096..].to_vec();
+        last_block.resize(2048, 0);
+        let (rsum, checksum) = block_sum(&last_block);
+        assert_eq!(&sums[10..12], &rsum[2..]);
+        assert_eq!(&sums[12..], &checksum[..3]);
+
+        assert_eq!(parse_zsync_header(&control.data).unwrap(), (2048, 5000));
+        assert!(parse_zsync_header(b"zsync: 0.6.2\n\n").is_err());
+    }
+
+    #[test]
+    fn it_computes_rsum() {
+        let (rsum, _) = block_sum(&[1, 2, 3]);
+
+        // a = 1 + 2 + 3, b = 3 * 1 + 2 * 2 + 1 * 3
+        assert_eq!(rsum, [0, 6, 0, 10]);
+    }
+}